@@ -0,0 +1,112 @@
+use tinypay::events;
+use tinypay::pipeline;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub fn app(router: pipeline::Router) -> axum::Router {
+    axum::Router::new()
+        .route("/events", axum::routing::post(submit_events))
+        .route("/accounts", axum::routing::get(list_accounts))
+        .route("/accounts/{client}", axum::routing::get(get_account))
+        .with_state(router)
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Submission {
+    Batch(Vec<events::Event>),
+    Single(events::Event),
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Outcomes {
+    Batch(Vec<events::Outcome>),
+    Single(events::Outcome),
+}
+
+// Events of a batch are processed in order, the response has an outcome for every event
+async fn submit_events(
+    axum::extract::State(router): axum::extract::State<pipeline::Router>,
+    axum::Json(submission): axum::Json<Submission>,
+) -> Result<axum::Json<Outcomes>, axum::http::StatusCode> {
+    let outcomes = match submission {
+        Submission::Single(event) => {
+            Outcomes::Single(router.submit(event).await.map_err(unavailable)?)
+        }
+        Submission::Batch(events) => {
+            let mut outcomes = Vec::with_capacity(events.len());
+            for event in events {
+                outcomes.push(router.submit(event).await.map_err(unavailable)?);
+            }
+            Outcomes::Batch(outcomes)
+        }
+    };
+    Ok(axum::Json(outcomes))
+}
+
+// Workers only stop after an error, the reason is reported when the server shuts down
+fn unavailable(_: pipeline::Error) -> axum::http::StatusCode {
+    axum::http::StatusCode::SERVICE_UNAVAILABLE
+}
+
+async fn get_account(
+    axum::extract::State(router): axum::extract::State<pipeline::Router>,
+    axum::extract::Path(client): axum::extract::Path<u16>,
+) -> Result<axum::Json<tinypay::accounts::Account>, axum::http::StatusCode> {
+    router
+        .account(client)
+        .await
+        .map_err(unavailable)?
+        .map(axum::Json)
+        .ok_or(axum::http::StatusCode::NOT_FOUND)
+}
+
+#[derive(serde::Deserialize)]
+struct Page {
+    // Client ID of the last account on the previous page
+    after: Option<u16>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct Accounts {
+    accounts: Vec<tinypay::accounts::Account>,
+    // Value of `after` for the next page, missing on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<u16>,
+}
+
+async fn list_accounts(
+    axum::extract::State(router): axum::extract::State<pipeline::Router>,
+    axum::extract::Query(page): axum::extract::Query<Page>,
+) -> Result<axum::Json<Accounts>, axum::http::StatusCode> {
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let accounts = router
+        .accounts(page.after, limit)
+        .await
+        .map_err(unavailable)?;
+    let next = if accounts.len() == limit {
+        accounts.last().map(|account| account.client)
+    } else {
+        None
+    };
+    Ok(axum::Json(Accounts { accounts, next }))
+}
+
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    router: pipeline::Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) {
+    if let Err(error) = axum::serve(listener, app(router))
+        .with_graceful_shutdown(shutdown)
+        .await
+    {
+        eprintln!("HTTP server failed: {}", error);
+    }
+}
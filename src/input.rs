@@ -0,0 +1,329 @@
+use std::io::BufRead;
+use std::io::Seek;
+
+use futures::StreamExt;
+use tinypay::events;
+
+// Large files are split into parts of about this size that are parsed in parallel
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl Format {
+    // Newline-delimited JSON is recognized by extension, anything else is read as CSV
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl" | "ndjson") => Format::Jsonl,
+            _ => Format::Csv,
+        }
+    }
+}
+
+pub enum Reader {
+    Csv(events::csv::CsvEventsReader<tokio::fs::File>),
+    Jsonl(events::jsonl::JsonlEventsReader<tokio::fs::File>),
+    Parts(Parts),
+}
+
+impl Reader {
+    pub fn new(format: Format, file: tokio::fs::File) -> Self {
+        match format {
+            Format::Csv => Reader::Csv(events::csv::CsvEventsReader::from_reader(file)),
+            Format::Jsonl => Reader::Jsonl(events::jsonl::JsonlEventsReader::from_reader(file)),
+        }
+    }
+
+    // Opens a file, splitting it into parts that are parsed in parallel if it is large enough
+    pub fn open(
+        format: Format,
+        path: &std::path::Path,
+        parallelism: usize,
+    ) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        // Pipes and other inputs that can not be seeked are read sequentially from the start
+        let metadata = file.metadata()?;
+        if parallelism < 2 || !metadata.is_file() || metadata.len() <= PART_SIZE {
+            return Ok(Reader::new(format, tokio::fs::File::from_std(file)));
+        }
+
+        let ranges = events::ranges::split(&mut file, PART_SIZE)?;
+        file.rewind()?;
+        if ranges.len() < 2 {
+            return Ok(Reader::new(format, tokio::fs::File::from_std(file)));
+        }
+
+        // Parts that start in the middle of a CSV file need to know whether the file has a header
+        let mut first_line = Vec::new();
+        std::io::BufReader::new(file).read_until(b'\n', &mut first_line)?;
+        let first_line = String::from_utf8_lossy(&first_line).into();
+
+        Ok(Reader::Parts(Parts::new(
+            format,
+            path.to_path_buf(),
+            first_line,
+            ranges,
+            parallelism,
+        )))
+    }
+
+    // Returns the header of CSV input
+    pub fn header(&self) -> Option<&str> {
+        match self {
+            Reader::Csv(reader) => Some(reader.header()),
+            Reader::Jsonl(_) => None,
+            Reader::Parts(parts) => parts.header.as_deref(),
+        }
+    }
+}
+
+impl events::EventSource for Reader {
+    async fn next_event(&mut self) -> Option<Result<events::Event, events::NextError>> {
+        match self {
+            Reader::Csv(reader) => reader.next_event().await,
+            Reader::Jsonl(reader) => reader.next_event().await,
+            Reader::Parts(parts) => parts.next_event().await,
+        }
+    }
+}
+
+// Parses parts of a file in parallel. Events of a part are kept in memory and returned in file order once all
+// preceding parts have been returned, so that events of every client keep their order.
+pub struct Parts {
+    parts: std::pin::Pin<Box<dyn futures::Stream<Item = Part> + Send>>,
+    format: Format,
+    path: std::path::PathBuf,
+    first_line: std::sync::Arc<str>,
+    events: std::vec::IntoIter<Result<events::Event, events::NextError>>,
+    header: Option<String>,
+    // Number of lines in parts that have been returned and in the current one
+    lines: u64,
+    part_lines: u64,
+}
+
+struct Part {
+    events: Vec<Result<events::Event, events::NextError>>,
+    lines: u64,
+    header: Option<String>,
+    range: std::ops::Range<u64>,
+    last: bool,
+}
+
+impl Parts {
+    fn new(
+        format: Format,
+        path: std::path::PathBuf,
+        first_line: std::sync::Arc<str>,
+        ranges: Vec<std::ops::Range<u64>>,
+        parallelism: usize,
+    ) -> Self {
+        let count = ranges.len();
+        let parts = futures::stream::iter(ranges.into_iter().enumerate())
+            .map({
+                let path = path.clone();
+                let first_line = first_line.clone();
+                move |(index, range)| {
+                    tokio::spawn(Part::parse(
+                        format,
+                        path.clone(),
+                        first_line.clone(),
+                        range,
+                        index + 1 == count,
+                    ))
+                }
+            })
+            .buffered(parallelism)
+            .map(|part| part.expect("parser is running"));
+        Self {
+            parts: Box::pin(parts),
+            format,
+            path,
+            first_line,
+            events: Vec::new().into_iter(),
+            header: None,
+            lines: 0,
+            part_lines: 0,
+        }
+    }
+}
+
+impl events::EventSource for Parts {
+    async fn next_event(&mut self) -> Option<Result<events::Event, events::NextError>> {
+        loop {
+            if let Some(event) = self.events.next() {
+                return Some(event.map_err(|mut error| {
+                    error.shift_lines(self.lines);
+                    error
+                }));
+            }
+
+            let mut part = self.parts.next().await?;
+            // Splitting does not know about quotes, so a part can end in the middle of a quoted line break. The next
+            // part then starts inside that record, both are parsed again from the start of the record.
+            while let Some((line, offset)) = part.split_record() {
+                let next = self
+                    .parts
+                    .next()
+                    .await
+                    .expect("only the last part ends at the end of input");
+                let rest = Part::parse(
+                    self.format,
+                    self.path.clone(),
+                    self.first_line.clone(),
+                    offset..next.range.end,
+                    next.last,
+                )
+                .await;
+                part.join(line, rest);
+            }
+
+            self.lines += self.part_lines;
+            self.part_lines = part.lines;
+            self.events = part.events.into_iter();
+            self.header = part.header;
+        }
+    }
+}
+
+impl Part {
+    async fn parse(
+        format: Format,
+        path: std::path::PathBuf,
+        first_line: std::sync::Arc<str>,
+        range: std::ops::Range<u64>,
+        last: bool,
+    ) -> Self {
+        let offset = range.start;
+        let file = match events::ranges::open(&path, range.clone()).await {
+            Ok(file) => file,
+            Err(source) => {
+                return Self {
+                    events: vec![Err(events::NextError::IO {
+                        line: 1,
+                        offset,
+                        raw: String::new(),
+                        source,
+                    })],
+                    lines: 0,
+                    header: None,
+                    range,
+                    last,
+                }
+            }
+        };
+
+        match format {
+            Format::Csv => {
+                let mut reader = if offset == 0 {
+                    events::csv::CsvEventsReader::from_reader(file)
+                } else {
+                    events::csv::CsvEventsReader::from_range(file, &first_line, offset)
+                };
+                Self {
+                    events: collect(&mut reader).await,
+                    lines: reader.line(),
+                    header: Some(reader.header().to_string()),
+                    range,
+                    last,
+                }
+            }
+            Format::Jsonl => {
+                let mut reader = events::jsonl::JsonlEventsReader::from_range(file, offset);
+                Self {
+                    events: collect(&mut reader).await,
+                    lines: reader.line(),
+                    header: None,
+                    range,
+                    last,
+                }
+            }
+        }
+    }
+
+    // Removes the record that is cut off by the end of the part, returning its line and offset. Only CSV parts
+    // that are followed by another part can end inside a quoted field.
+    fn split_record(&mut self) -> Option<(u64, u64)> {
+        if self.last {
+            return None;
+        }
+        let Some(Err(
+            error @ events::NextError::Parse {
+                source: events::ParseError::Csv(events::csv::FromCsvRowError::UnterminatedQuote),
+                ..
+            },
+        )) = self.events.last()
+        else {
+            return None;
+        };
+        let split = (error.line(), error.offset());
+        self.events.pop();
+        Some(split)
+    }
+
+    // Replaces the end of the part, starting from the given line, with a part parsed from there
+    fn join(&mut self, line: u64, rest: Part) {
+        let lines = line - 1;
+        self.events.extend(rest.events.into_iter().map(|event| {
+            event.map_err(|mut error| {
+                error.shift_lines(lines);
+                error
+            })
+        }));
+        self.lines = lines + rest.lines;
+        self.header = rest.header;
+        self.range.end = rest.range.end;
+        self.last = rest.last;
+    }
+}
+
+async fn collect(
+    source: &mut impl events::EventSource,
+) -> Vec<Result<events::Event, events::NextError>> {
+    let mut events = Vec::new();
+    while let Some(event) = source.next_event().await {
+        events.push(event);
+    }
+    events
+}
+
+// Quarantined rows are written in the format of the input, so that they can be fixed and fed back
+pub enum Quarantine {
+    Csv(events::csv::QuarantineCsvWriter<std::io::BufWriter<std::fs::File>>),
+    Jsonl(events::jsonl::QuarantineJsonlWriter<std::io::BufWriter<std::fs::File>>),
+}
+
+impl Quarantine {
+    pub fn create(format: Format, path: &std::path::Path) -> Self {
+        let Ok(file) = std::fs::File::create(path) else {
+            eprintln!("Failed to create quarantine file: {}", path.display());
+            std::process::exit(1);
+        };
+        let writer = std::io::BufWriter::new(file);
+        match format {
+            Format::Csv => Quarantine::Csv(events::csv::QuarantineCsvWriter::from_writer(writer)),
+            Format::Jsonl => {
+                Quarantine::Jsonl(events::jsonl::QuarantineJsonlWriter::from_writer(writer))
+            }
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        reader: &Reader,
+        raw: &str,
+        error: &events::ParseError,
+    ) -> std::io::Result<()> {
+        match (self, reader.header(), error) {
+            (Quarantine::Csv(writer), Some(header), events::ParseError::Csv(error)) => {
+                writer.write_quarantined(header, raw, error)
+            }
+            (Quarantine::Jsonl(writer), _, events::ParseError::Jsonl(error)) => {
+                writer.write_quarantined(raw, error)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,22 +1,37 @@
 pub mod csv;
+pub mod json;
 
-#[derive(Debug)]
+use crate::amount::Amount;
+
+// Destination of account balances in any output format
+pub trait AccountSink {
+    fn write_account(&mut self, account: &Account) -> std::io::Result<()>;
+
+    // Called once after the last account has been written
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Account {
     pub client: u16,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
     pub locked: bool,
+    pub closed: bool,
 }
 
 impl Account {
     pub fn new(client: u16) -> Self {
         Self {
             client,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
+            closed: false,
         }
     }
 }
@@ -20,8 +20,14 @@ impl<R: std::io::Write> AccountsCsvWriter<R> {
         }
         writeln!(
             self.writer,
-            "{},{:.4},{:.4},{:.4},{}",
+            "{},{},{},{},{}",
             account.client, account.available, account.held, account.total, account.locked
         )
     }
 }
+
+impl<R: std::io::Write> accounts::AccountSink for AccountsCsvWriter<R> {
+    fn write_account(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        AccountsCsvWriter::write_account(self, account)
+    }
+}
@@ -0,0 +1,97 @@
+use crate::accounts;
+use crate::amount::Amount;
+
+// Accounts are written with the same columns as in CSV output
+#[derive(serde::Serialize)]
+struct Row {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl From<&accounts::Account> for Row {
+    fn from(account: &accounts::Account) -> Self {
+        Self {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
+
+// Writes accounts as a single JSON array, finish must be called to close it
+pub struct AccountsJsonWriter<W: std::io::Write> {
+    writer: W,
+    opened: bool,
+    finished: bool,
+}
+
+impl<W: std::io::Write> AccountsJsonWriter<W> {
+    pub fn from_writer(writer: W) -> Self {
+        Self {
+            writer,
+            opened: false,
+            finished: false,
+        }
+    }
+
+    pub fn write_account(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        if self.opened {
+            writeln!(self.writer, ",")?;
+        } else {
+            writeln!(self.writer, "[")?;
+            self.opened = true;
+        }
+        serde_json::to_writer(&mut self.writer, &Row::from(account))?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if self.opened {
+            writeln!(self.writer, "\n]")?;
+        } else {
+            writeln!(self.writer, "[]")?;
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+// Writes one JSON object per account per line
+pub struct AccountsNdjsonWriter<W: std::io::Write> {
+    writer: W,
+}
+
+impl<W: std::io::Write> AccountsNdjsonWriter<W> {
+    pub fn from_writer(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write_account(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &Row::from(account))?;
+        writeln!(self.writer)
+    }
+}
+
+impl<W: std::io::Write> accounts::AccountSink for AccountsJsonWriter<W> {
+    fn write_account(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        AccountsJsonWriter::write_account(self, account)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        AccountsJsonWriter::finish(self)
+    }
+}
+
+impl<W: std::io::Write> accounts::AccountSink for AccountsNdjsonWriter<W> {
+    fn write_account(&mut self, account: &accounts::Account) -> std::io::Result<()> {
+        AccountsNdjsonWriter::write_account(self, account)
+    }
+}
@@ -0,0 +1,158 @@
+// Number of minor units in one whole unit, i.e. amounts have four decimal places.
+const SCALE: i64 = 10_000;
+const DECIMAL_PLACES: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("amount overflow")]
+pub struct OverflowError;
+
+impl Amount {
+    pub const ZERO: Self = Self(0);
+
+    pub fn from_minor_units(units: i64) -> Self {
+        Self(units)
+    }
+
+    pub fn minor_units(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, OverflowError> {
+        self.0.checked_add(other.0).map(Self).ok_or(OverflowError)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, OverflowError> {
+        self.0.checked_sub(other.0).map(Self).ok_or(OverflowError)
+    }
+
+    pub fn checked_neg(self) -> Result<Self, OverflowError> {
+        self.0.checked_neg().map(Self).ok_or(OverflowError)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseAmountError {
+    #[error("empty amount")]
+    Empty,
+    #[error("invalid digit in amount")]
+    InvalidDigit,
+    #[error("amount has more than {DECIMAL_PLACES} decimal places")]
+    TooPrecise,
+    #[error("amount is out of range")]
+    Overflow,
+}
+
+impl std::str::FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(ParseAmountError::InvalidDigit);
+        }
+
+        // Trailing zeros past the fourth decimal place do not change the value
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > DECIMAL_PLACES {
+            return Err(ParseAmountError::TooPrecise);
+        }
+
+        let mut units: i64 = 0;
+        for digit in integer.bytes() {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(i64::from(digit - b'0')))
+                .ok_or(ParseAmountError::Overflow)?;
+        }
+        units = units.checked_mul(SCALE).ok_or(ParseAmountError::Overflow)?;
+
+        let mut fraction_units: i64 = 0;
+        for digit in fraction.bytes() {
+            fraction_units = fraction_units * 10 + i64::from(digit - b'0');
+        }
+        fraction_units *= 10_i64.pow((DECIMAL_PLACES - fraction.len()) as u32);
+
+        let units = units
+            .checked_add(fraction_units)
+            .ok_or(ParseAmountError::Overflow)?;
+        Ok(Self(if negative { -units } else { units }))
+    }
+}
+
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            units / scale,
+            units % scale,
+            width = DECIMAL_PLACES
+        )
+    }
+}
+
+// Amounts are serialized as decimal strings so that no precision is lost on the way
+impl serde::Serialize for Amount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Numbers are accepted too, floats are converted through their shortest decimal representation
+impl<'de> serde::Deserialize<'de> for Amount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a decimal amount with at most four decimal places")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Amount, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Amount, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Amount, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Amount, E> {
+                if !v.is_finite() {
+                    return Err(E::custom(ParseAmountError::InvalidDigit));
+                }
+                self.visit_str(&v.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
@@ -1,138 +1,713 @@
 pub mod csv;
+pub mod journal;
+pub mod jsonl;
+pub mod ranges;
+pub mod snapshot;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 
 use crate::accounts;
+use crate::amount::Amount;
+use crate::policy;
 
-#[derive(Debug)]
-pub struct Transaction {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Deposit {
+    #[serde(rename = "tx")]
     pub id: u32,
+    #[serde(rename = "client")]
     pub client_id: u16,
-    pub amount: f64,
+    pub amount: Amount,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Withdrawal {
+    #[serde(rename = "tx")]
+    pub id: u32,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub amount: Amount,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Dispute {
+    #[serde(rename = "tx")]
     pub transaction_id: u32,
+    #[serde(rename = "client")]
     pub client_id: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Resolve {
+    #[serde(rename = "tx")]
     pub transaction_id: u32,
+    #[serde(rename = "client")]
     pub client_id: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Chargeback {
+    #[serde(rename = "tx")]
     pub transaction_id: u32,
+    #[serde(rename = "client")]
     pub client_id: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Unlock {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Freeze {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Close {
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
-    Transaction(Transaction),
+    Deposit(Deposit),
+    Withdrawal(Withdrawal),
     Dispute(Dispute),
     Resolve(Resolve),
     Chargeback(Chargeback),
+    Unlock(Unlock),
+    Freeze(Freeze),
+    Close(Close),
 }
 
 impl Event {
     pub fn client_id(&self) -> u16 {
         match self {
-            Event::Transaction(transaction) => transaction.client_id,
+            Event::Deposit(deposit) => deposit.client_id,
+            Event::Withdrawal(withdrawal) => withdrawal.client_id,
             Event::Dispute(dispute) => dispute.client_id,
             Event::Resolve(resolve) => resolve.client_id,
             Event::Chargeback(chargeback) => chargeback.client_id,
+            Event::Unlock(unlock) => unlock.client_id,
+            Event::Freeze(freeze) => freeze.client_id,
+            Event::Close(close) => close.client_id,
         }
     }
 }
 
-pub fn reduce(events: &[Event]) -> Vec<accounts::Account> {
-    let mut accounts = std::collections::HashMap::<u16, accounts::Account>::new();
-    let mut transactions = std::collections::HashMap::<u16, HashMap<u32, f64>>::new();
-    let mut disputes = std::collections::HashMap::<u16, HashSet<u32>>::new();
-    for event in events {
-        let client_id = event.client_id();
+// Error of a line based event reader. Line is 1-based and offset is the byte offset of the start of
+// the record, raw is the record as it was read
+#[derive(Debug, thiserror::Error)]
+pub enum NextError {
+    #[error("line {line}, offset {offset}: failed to parse event: {source}: {raw}")]
+    Parse {
+        line: u64,
+        offset: u64,
+        raw: String,
+        source: ParseError,
+    },
+    #[error("line {line}, offset {offset}: failed to read event: {source}")]
+    IO {
+        line: u64,
+        offset: u64,
+        raw: String,
+        source: std::io::Error,
+    },
+}
+
+impl NextError {
+    pub fn line(&self) -> u64 {
+        match self {
+            Self::Parse { line, .. } | Self::IO { line, .. } => *line,
+        }
+    }
 
-        let account = accounts
+    pub fn offset(&self) -> u64 {
+        match self {
+            Self::Parse { offset, .. } | Self::IO { offset, .. } => *offset,
+        }
+    }
+
+    pub fn raw(&self) -> &str {
+        match self {
+            Self::Parse { raw, .. } | Self::IO { raw, .. } => raw,
+        }
+    }
+
+    // Moves the error down by the given number of lines, for errors of a reader that started in the middle of input
+    pub fn shift_lines(&mut self, lines: u64) {
+        match self {
+            Self::Parse { line, .. } | Self::IO { line, .. } => *line += lines,
+        }
+    }
+}
+
+// Source of events in any input format
+pub trait EventSource {
+    fn next_event(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<Event, NextError>>> + Send;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error(transparent)]
+    Csv(#[from] csv::FromCsvRowError),
+    #[error(transparent)]
+    Jsonl(#[from] jsonl::FromJsonLineError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    Rejected(RejectionReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    DuplicateTransaction,
+    NonPositiveAmount,
+    InsufficientFunds,
+    UnknownTransaction,
+    NotDisputable,
+    AlreadyDisputed,
+    NotDisputed,
+    DisputeClosed,
+    AccountLocked,
+    NotLocked,
+    AccountClosed,
+    Overflow,
+}
+
+impl RejectionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::DuplicateTransaction => "duplicate_transaction",
+            RejectionReason::NonPositiveAmount => "non_positive_amount",
+            RejectionReason::InsufficientFunds => "insufficient_funds",
+            RejectionReason::UnknownTransaction => "unknown_transaction",
+            RejectionReason::NotDisputable => "not_disputable",
+            RejectionReason::AlreadyDisputed => "already_disputed",
+            RejectionReason::NotDisputed => "not_disputed",
+            RejectionReason::DisputeClosed => "dispute_closed",
+            RejectionReason::AccountLocked => "account_locked",
+            RejectionReason::NotLocked => "not_locked",
+            RejectionReason::AccountClosed => "account_closed",
+            RejectionReason::Overflow => "overflow",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Lifecycle of a stored transaction:
+//
+// Settled -> Disputed -> Resolved
+//                     -> ChargedBack
+//
+// Resolved and ChargedBack are final, a transaction can be disputed only once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl TransactionState {
+    pub fn dispute(self) -> Result<Self, RejectionReason> {
+        match self {
+            TransactionState::Settled => Ok(TransactionState::Disputed),
+            TransactionState::Disputed => Err(RejectionReason::AlreadyDisputed),
+            TransactionState::Resolved | TransactionState::ChargedBack => {
+                Err(RejectionReason::DisputeClosed)
+            }
+        }
+    }
+
+    pub fn resolve(self) -> Result<Self, RejectionReason> {
+        match self {
+            TransactionState::Disputed => Ok(TransactionState::Resolved),
+            _ => Err(RejectionReason::NotDisputed),
+        }
+    }
+
+    pub fn chargeback(self) -> Result<Self, RejectionReason> {
+        match self {
+            TransactionState::Disputed => Ok(TransactionState::ChargedBack),
+            _ => Err(RejectionReason::NotDisputed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    #[default]
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StoredTransaction {
+    kind: TransactionKind,
+    amount: Amount,
+    state: TransactionState,
+}
+
+#[derive(Debug)]
+struct Client {
+    account: accounts::Account,
+    transactions: HashMap<u32, StoredTransaction>,
+}
+
+impl Client {
+    fn new(client_id: u16) -> Self {
+        Self {
+            account: accounts::Account::new(client_id),
+            transactions: HashMap::new(),
+        }
+    }
+
+    fn commit(&mut self, change: Change) {
+        self.account = change.account;
+        if let Some((id, transaction)) = change.transaction {
+            self.transactions.insert(id, transaction);
+        }
+    }
+}
+
+// Result of a validated event, computed without touching the engine state
+struct Change {
+    account: accounts::Account,
+    transaction: Option<(u32, StoredTransaction)>,
+    // Set if the event itself is not applied, but the account still changes because of it
+    rejected: Option<RejectionReason>,
+}
+
+// Folds events into account state one at a time, so memory is bounded by the number of
+// accounts and stored transactions rather than by the number of events.
+#[derive(Debug, Default)]
+pub struct Engine {
+    policy: policy::Policy,
+    // Ordered by client id, so that accounts are always returned in the same order
+    clients: BTreeMap<u16, Client>,
+    // Sequence number of the last journal entry reflected in the state
+    sequence: u64,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_policy(policy: policy::Policy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> &policy::Policy {
+        &self.policy
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn account(&self, client_id: u16) -> Option<&accounts::Account> {
+        self.clients.get(&client_id).map(|client| &client.account)
+    }
+
+    // Both accounts and into_accounts return accounts in ascending order of client id
+    pub fn accounts(&self) -> impl Iterator<Item = &accounts::Account> {
+        self.clients.values().map(|client| &client.account)
+    }
+
+    pub fn into_accounts(self) -> impl Iterator<Item = accounts::Account> {
+        self.clients.into_values().map(|client| client.account)
+    }
+
+    pub fn transaction_state(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Option<TransactionState> {
+        self.clients
+            .get(&client_id)
+            .and_then(|client| client.transactions.get(&transaction_id))
+            .map(|transaction| transaction.state)
+    }
+
+    pub fn snapshot(&self) -> snapshot::Snapshot {
+        let mut snapshot = snapshot::Snapshot::new(
+            self.clients
+                .values()
+                .map(|client| snapshot::Client {
+                    account: client.account.clone(),
+                    transactions: client
+                        .transactions
+                        .iter()
+                        .map(|(id, transaction)| snapshot::Transaction {
+                            id: *id,
+                            kind: transaction.kind,
+                            amount: transaction.amount,
+                            state: transaction.state,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        );
+        snapshot.sequence = self.sequence;
+        snapshot
+    }
+
+    // Replaces the state of every client present in the snapshot
+    pub fn restore(&mut self, snapshot: snapshot::Snapshot) {
+        self.sequence = self.sequence.max(snapshot.sequence);
+        for client in snapshot.clients {
+            self.clients.insert(
+                client.account.client,
+                Client {
+                    account: client.account,
+                    transactions: client
+                        .transactions
+                        .into_iter()
+                        .map(|transaction| {
+                            (
+                                transaction.id,
+                                StoredTransaction {
+                                    kind: transaction.kind,
+                                    amount: transaction.amount,
+                                    state: transaction.state,
+                                },
+                            )
+                        })
+                        .collect(),
+                },
+            );
+        }
+    }
+
+    pub fn apply(&mut self, event: Event) -> Outcome {
+        let change = self.plan(&event);
+        self.commit(event.client_id(), change)
+    }
+
+    // Appends the event to the journal before changing the state, events that change nothing are not journaled
+    pub fn apply_journaled<W: std::io::Write>(
+        &mut self,
+        event: Event,
+        journal: &mut journal::Journal<W>,
+    ) -> std::io::Result<Outcome> {
+        let change = self.plan(&event);
+        if change.is_ok() {
+            self.sequence = journal.append(&event)?;
+        }
+        Ok(self.commit(event.client_id(), change))
+    }
+
+    // Applies an entry read back from the journal, skipping entries that are already part of the state
+    pub fn replay(&mut self, entry: journal::Entry) -> Option<Outcome> {
+        if entry.sequence <= self.sequence {
+            return None;
+        }
+        self.sequence = entry.sequence;
+        Some(self.apply(entry.event))
+    }
+
+    fn commit(&mut self, client_id: u16, change: Result<Change, RejectionReason>) -> Outcome {
+        // Every client that has been seen gets an account, even if none of its events were applied
+        let client = self
+            .clients
             .entry(client_id)
-            .or_insert_with(|| accounts::Account::new(client_id));
+            .or_insert_with(|| Client::new(client_id));
+        match change {
+            Ok(change) => {
+                let outcome = change.rejected.map_or(Outcome::Applied, Outcome::Rejected);
+                client.commit(change);
+                outcome
+            }
+            Err(reason) => Outcome::Rejected(reason),
+        }
+    }
+
+    fn plan(&self, event: &Event) -> Result<Change, RejectionReason> {
+        let client_id = event.client_id();
+        let client = self.clients.get(&client_id);
+        let mut account = client
+            .map(|client| client.account.clone())
+            .unwrap_or_else(|| accounts::Account::new(client_id));
+        let stored_transaction = |transaction_id: u32| {
+            client
+                .and_then(|client| client.transactions.get(&transaction_id))
+                .copied()
+        };
+
+        if account.closed {
+            // Reject all events for closed accounts, including administrative ones
+            return Err(RejectionReason::AccountClosed);
+        }
 
         if account.locked {
-            // Ignore events for locked accounts
-            continue;
+            let accepted = match (self.policy.locked_accounts, event) {
+                // Administrative events are always accepted, they are the way to handle locked accounts
+                (_, Event::Unlock(_) | Event::Freeze(_) | Event::Close(_)) => true,
+                (policy::LockedAccounts::RejectAll, _) => false,
+                (policy::LockedAccounts::AcceptDeposits, Event::Deposit(_)) => true,
+                (policy::LockedAccounts::AcceptDeposits, _) => false,
+                (
+                    policy::LockedAccounts::AcceptDisputes,
+                    Event::Deposit(_) | Event::Withdrawal(_),
+                ) => false,
+                (policy::LockedAccounts::AcceptDisputes, _) => true,
+            };
+            if !accepted {
+                // Reject events for locked accounts
+                return Err(RejectionReason::AccountLocked);
+            }
         }
 
-        let account_transactions = transactions.entry(client_id).or_default();
-        let account_disputes = disputes.entry(client_id).or_default();
+        let transaction = match event {
+            Event::Deposit(deposit) => {
+                if stored_transaction(deposit.id).is_some() {
+                    // Reject transactions that have already been processed
+                    return Err(RejectionReason::DuplicateTransaction);
+                }
 
-        match event {
-            Event::Transaction(transaction) => {
-                if account_transactions.contains_key(&transaction.id) {
-                    // Ignore transactions that have already been processed
-                    continue;
+                if deposit.amount <= Amount::ZERO {
+                    // Reject deposits that would take funds from the account
+                    return Err(RejectionReason::NonPositiveAmount);
                 }
 
-                if account.available + transaction.amount < 0.0 {
-                    // Ignore withdrawal transactions that would result in a negative available balance
-                    continue;
+                let (Ok(available), Ok(total)) = (
+                    account.available.checked_add(deposit.amount),
+                    account.total.checked_add(deposit.amount),
+                ) else {
+                    // Reject deposits that would overflow the balance
+                    return Err(RejectionReason::Overflow);
+                };
+
+                account.available = available;
+                account.total = total;
+                Some((
+                    deposit.id,
+                    StoredTransaction {
+                        kind: TransactionKind::Deposit,
+                        amount: deposit.amount,
+                        state: TransactionState::Settled,
+                    },
+                ))
+            }
+            Event::Withdrawal(withdrawal) => {
+                if stored_transaction(withdrawal.id).is_some() {
+                    // Reject transactions that have already been processed
+                    return Err(RejectionReason::DuplicateTransaction);
+                }
+
+                if withdrawal.amount <= Amount::ZERO {
+                    // Reject withdrawals that would add funds to the account
+                    return Err(RejectionReason::NonPositiveAmount);
                 }
 
-                account.available += transaction.amount;
-                account.total += transaction.amount;
-                account_transactions.insert(transaction.id, transaction.amount);
+                let (Ok(available), Ok(total)) = (
+                    account.available.checked_sub(withdrawal.amount),
+                    account.total.checked_sub(withdrawal.amount),
+                ) else {
+                    // Reject withdrawals that would overflow the balance
+                    return Err(RejectionReason::Overflow);
+                };
+
+                if available.is_negative() {
+                    // Reject withdrawals that would result in a negative available balance
+                    return Err(RejectionReason::InsufficientFunds);
+                }
+
+                account.available = available;
+                account.total = total;
+                Some((
+                    withdrawal.id,
+                    StoredTransaction {
+                        kind: TransactionKind::Withdrawal,
+                        amount: withdrawal.amount,
+                        state: TransactionState::Settled,
+                    },
+                ))
             }
             Event::Dispute(dispute) => {
-                let Some(transaction_amount) = account_transactions.get(&dispute.transaction_id)
-                else {
-                    // Ignore disputes for transactions that do not exist
-                    continue;
+                let Some(mut transaction) = stored_transaction(dispute.transaction_id) else {
+                    // Reject disputes for transactions that do not exist
+                    return Err(RejectionReason::UnknownTransaction);
+                };
+
+                if transaction.kind == TransactionKind::Withdrawal
+                    && !self.policy.disputable_withdrawals
+                {
+                    // Reject disputes for withdrawals unless the policy allows them
+                    return Err(RejectionReason::NotDisputable);
+                }
+
+                transaction.state = transaction.state.dispute()?;
+
+                let amount = transaction.amount;
+                let (available, held, total) = match transaction.kind {
+                    // Disputed deposit is moved from available to held funds
+                    TransactionKind::Deposit => (
+                        account.available.checked_sub(amount),
+                        account.held.checked_add(amount),
+                        Ok(account.total),
+                    ),
+                    // Disputed withdrawal is held until the dispute is settled, so it can not be spent
+                    TransactionKind::Withdrawal => (
+                        Ok(account.available),
+                        account.held.checked_add(amount),
+                        account.total.checked_add(amount),
+                    ),
+                };
+                let (Ok(available), Ok(held), Ok(total)) = (available, held, total) else {
+                    // Reject disputes that would overflow the balance
+                    return Err(RejectionReason::Overflow);
                 };
-                account.available -= transaction_amount;
-                account.held += transaction_amount;
-                account_disputes.insert(dispute.transaction_id);
+
+                if available.is_negative() && !self.policy.negative_available_on_dispute {
+                    // Reject disputes for funds that have already been withdrawn
+                    return Err(RejectionReason::InsufficientFunds);
+                }
+
+                account.available = available;
+                account.held = held;
+                account.total = total;
+                Some((dispute.transaction_id, transaction))
             }
             Event::Resolve(resolve) => {
-                if !account_disputes.contains(&resolve.transaction_id) {
-                    // Ignore resolves for transactions that are not in dispute
-                    continue;
-                }
+                let Some(mut transaction) = stored_transaction(resolve.transaction_id) else {
+                    // Reject resolves for transactions that do not exist
+                    return Err(RejectionReason::UnknownTransaction);
+                };
 
-                let Some(transaction_amount) = account_transactions.get(&resolve.transaction_id)
-                else {
-                    // Ignore resolves for transactions that do not exist
-                    continue;
+                transaction.state = transaction.state.resolve()?;
+
+                let amount = transaction.amount;
+                let (available, held, total) = match transaction.kind {
+                    // Resolved deposit is released back to available funds
+                    TransactionKind::Deposit => (
+                        account.available.checked_add(amount),
+                        account.held.checked_sub(amount),
+                        Ok(account.total),
+                    ),
+                    // Resolved withdrawal stands, so held funds leave the account
+                    TransactionKind::Withdrawal => (
+                        Ok(account.available),
+                        account.held.checked_sub(amount),
+                        account.total.checked_sub(amount),
+                    ),
+                };
+                let (Ok(available), Ok(held), Ok(total)) = (available, held, total) else {
+                    // Reject resolves that would overflow the balance
+                    return Err(RejectionReason::Overflow);
                 };
 
-                account.available += transaction_amount;
-                account.held -= transaction_amount;
-                account_disputes.remove(&resolve.transaction_id);
+                account.available = available;
+                account.held = held;
+                account.total = total;
+                Some((resolve.transaction_id, transaction))
             }
             Event::Chargeback(chargeback) => {
-                let Some(transaction_amount) = account_transactions.get(&chargeback.transaction_id)
-                else {
-                    // Ignore chargebacks for transactions that do not exist
-                    continue;
+                let Some(mut transaction) = stored_transaction(chargeback.transaction_id) else {
+                    // Reject chargebacks for transactions that do not exist
+                    return Err(RejectionReason::UnknownTransaction);
                 };
 
-                if !account_disputes.contains(&chargeback.transaction_id) {
-                    // Chargeback for transactions that are not in dispute are ignored
-                    continue;
-                }
+                transaction.state = transaction.state.chargeback()?;
 
-                if account.total - transaction_amount < 0.0 {
-                    // If a chargeback leads to negative balance, lock the account
-                    account.locked = true;
-                    continue;
+                let amount = transaction.amount;
+                let (available, held, total) = match transaction.kind {
+                    // Charged back deposit is reversed, held funds leave the account
+                    TransactionKind::Deposit => (
+                        Ok(account.available),
+                        account.held.checked_sub(amount),
+                        account.total.checked_sub(amount),
+                    ),
+                    // Charged back withdrawal is refunded, held funds become available
+                    TransactionKind::Withdrawal => (
+                        account.available.checked_add(amount),
+                        account.held.checked_sub(amount),
+                        Ok(account.total),
+                    ),
+                };
+                let (Ok(available), Ok(held), Ok(total)) = (available, held, total) else {
+                    // Reject chargebacks that would overflow the balance
+                    return Err(RejectionReason::Overflow);
+                };
+
+                match self.policy.lock_on {
+                    policy::LockOn::NegativeBalance if total.is_negative() => {
+                        // If a chargeback leads to negative balance, lock the account without moving the funds
+                        account.locked = true;
+                        return Ok(Change {
+                            account,
+                            transaction: None,
+                            rejected: Some(RejectionReason::InsufficientFunds),
+                        });
+                    }
+                    policy::LockOn::NegativeBalance => {}
+                    policy::LockOn::AnyChargeback => account.locked = true,
                 }
 
-                account.held -= transaction_amount;
-                account.total -= transaction_amount;
-                account_disputes.remove(&chargeback.transaction_id);
-                account_transactions.remove(&chargeback.transaction_id);
+                account.available = available;
+                account.held = held;
+                account.total = total;
+                Some((chargeback.transaction_id, transaction))
             }
-        }
+            Event::Unlock(_) => {
+                if !account.locked {
+                    // Reject unlocks for accounts that are not locked
+                    return Err(RejectionReason::NotLocked);
+                }
+                account.locked = false;
+                None
+            }
+            Event::Freeze(_) => {
+                if account.locked {
+                    // Reject freezes for accounts that are already locked
+                    return Err(RejectionReason::AccountLocked);
+                }
+                account.locked = true;
+                None
+            }
+            Event::Close(_) => {
+                account.locked = true;
+                account.closed = true;
+                None
+            }
+        };
+        Ok(Change {
+            account,
+            transaction,
+            rejected: None,
+        })
+    }
+}
+
+// Returns accounts in ascending order of client id
+pub fn reduce(events: &[Event]) -> Vec<accounts::Account> {
+    let mut engine = Engine::new();
+    for event in events {
+        engine.apply(event.clone());
     }
-    accounts.into_values().collect()
+    engine.into_accounts().collect()
 }
@@ -1,9 +1,22 @@
-use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncBufRead;
 
+use crate::amount::Amount;
 use crate::events;
 
 pub struct CsvEventsReader<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> {
-    lines: tokio::io::Lines<tokio::io::BufReader<R>>,
+    reader: tokio::io::BufReader<R>,
+    // Bytes of the record being read, up to the maximum record length, and the number of bytes consumed for it
+    record: Vec<u8>,
+    length: usize,
+    quoting: Quoting,
+    // Number of lines and bytes consumed so far
+    line: u64,
+    offset: u64,
+    // Known after the first record, which is either a header or the first row in the default column order
+    columns: Option<Columns>,
+    header: Option<String>,
+    // Set after a read error, the input is not read any further
+    failed: bool,
 }
 
 impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> CsvEventsReader<R> {
@@ -11,29 +24,441 @@ impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> CsvEventsRea
         const BUFFER_SIZE: usize = 8 * 1024;
         let reader = tokio::io::BufReader::with_capacity(BUFFER_SIZE, reader);
         Self {
-            lines: reader.lines(),
+            reader,
+            record: Vec::new(),
+            length: 0,
+            quoting: Quoting::FieldStart,
+            line: 0,
+            offset: 0,
+            columns: None,
+            header: None,
+            failed: false,
         }
     }
+
+    // Reads a part of an input that starts at a line boundary at the given offset. The first line of the input tells
+    // whether it has a header, line numbers are counted from the start of the part.
+    pub fn from_range(reader: R, first_line: &str, offset: u64) -> Self {
+        let first_line = first_line.strip_prefix('\u{FEFF}').unwrap_or(first_line);
+        let first_line = first_line.trim_end_matches(['\r', '\n']);
+        let columns = Columns::from_header(&split_record(first_line));
+        Self {
+            offset,
+            header: columns.as_ref().map(|_| first_line.to_string()),
+            columns: Some(columns.unwrap_or_default()),
+            ..Self::from_reader(reader)
+        }
+    }
+
+    // Number of lines read so far
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    // Returns the header of the input, or the header of the default column order if the input has none
+    pub fn header(&self) -> &str {
+        self.header.as_deref().unwrap_or(DEFAULT_HEADER)
+    }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum NextError {
-    #[error("failed to parse event: {0}")]
-    Parse(FromCsvRowError),
-    #[error("failed to read event: {0}")]
-    IO(std::io::Error),
+const DEFAULT_HEADER: &str = "type,client,tx,amount,reason";
+
+// Longer records are reported as malformed, so that a quoted field that is never closed can not take up
+// the rest of the input
+const MAX_RECORD_LENGTH: usize = 64 * 1024;
+
+// Position in a record, a field is only quoted if it starts with a quote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quoting {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    // A quote in a quoted field, either closing it or escaping the next quote
+    QuotedQuote,
+}
+
+impl Quoting {
+    fn next(self, byte: u8) -> Self {
+        match (self, byte) {
+            (Quoting::Quoted, b'"') => Quoting::QuotedQuote,
+            (Quoting::Quoted, _) => Quoting::Quoted,
+            (Quoting::QuotedQuote, b'"') => Quoting::Quoted,
+            (_, b',') => Quoting::FieldStart,
+            (Quoting::FieldStart, b'"') => Quoting::Quoted,
+            (Quoting::FieldStart, b' ' | b'\t') => Quoting::FieldStart,
+            _ => Quoting::Unquoted,
+        }
+    }
+}
+
+struct Record {
+    line: u64,
+    offset: u64,
+    raw: String,
+    fields: Vec<String>,
 }
 
 impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> CsvEventsReader<R> {
-    pub async fn next_event(&mut self) -> Option<Result<events::Event, NextError>> {
-        match self.lines.next_line().await {
-            Ok(Some(line)) => match from_csv_row(&line) {
-                Ok(event) => Some(Ok(event)),
-                Err(error) => Some(Err(NextError::Parse(error))),
-            },
-            Ok(None) => None,
-            Err(error) => Some(Err(NextError::IO(error))),
+    pub async fn next_event(&mut self) -> Option<Result<events::Event, events::NextError>> {
+        std::future::poll_fn(|cx| self.poll_next_event(cx)).await
+    }
+
+    fn poll_next_event(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<events::Event, events::NextError>>> {
+        loop {
+            if self.failed {
+                return std::task::Poll::Ready(None);
+            }
+            let result = std::task::ready!(self.poll_fill_record(cx));
+            let record = match self.take_record(result) {
+                Some(Ok(record)) => record,
+                Some(Err(error)) => {
+                    self.failed = matches!(error, events::NextError::IO { .. });
+                    return std::task::Poll::Ready(Some(Err(error)));
+                }
+                None => return std::task::Poll::Ready(None),
+            };
+
+            if record.fields.len() == 1 && record.fields[0].is_empty() {
+                // Skip blank lines
+                continue;
+            }
+
+            let columns = match &self.columns {
+                Some(columns) => columns,
+                None => match Columns::from_header(&record.fields) {
+                    Some(columns) => {
+                        self.columns = Some(columns);
+                        self.header = Some(record.raw);
+                        continue;
+                    }
+                    None => self.columns.insert(Columns::default()),
+                },
+            };
+
+            return std::task::Poll::Ready(Some(from_csv_row(&record.fields, columns).map_err(
+                |source| events::NextError::Parse {
+                    line: record.line,
+                    offset: record.offset,
+                    raw: record.raw,
+                    source: source.into(),
+                },
+            )));
+        }
+    }
+
+    // Reads into the record buffer until it holds a single RFC 4180 record, which spans multiple lines if
+    // a quoted field contains line breaks. The buffer is kept between polls, so no input is lost if the
+    // reader is not ready.
+    fn poll_fill_record(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        loop {
+            let available =
+                std::task::ready!(std::pin::Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return std::task::Poll::Ready(Ok(()));
+            }
+
+            let mut newline = None;
+            for (index, byte) in available.iter().enumerate() {
+                if self.length + index >= MAX_RECORD_LENGTH && self.quoting == Quoting::Quoted {
+                    // Give up on a quoted field that is never closed, the record ends at the next line break
+                    self.quoting = Quoting::Unquoted;
+                }
+                if *byte == b'\n' && self.quoting != Quoting::Quoted {
+                    newline = Some(index);
+                    break;
+                }
+                self.quoting = self.quoting.next(*byte);
+            }
+
+            let length = newline.map_or(available.len(), |position| position + 1);
+            // Bytes over the maximum record length are consumed, but not kept
+            let kept = length.min(MAX_RECORD_LENGTH.saturating_sub(self.record.len()));
+            self.record.extend_from_slice(&available[..kept]);
+            self.length += length;
+            std::pin::Pin::new(&mut self.reader).consume(length);
+
+            if newline.is_some() {
+                return std::task::Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    // Parses the record buffer and clears it for the next record
+    fn take_record(
+        &mut self,
+        result: std::io::Result<()>,
+    ) -> Option<Result<Record, events::NextError>> {
+        let line = self.line + 1;
+        let offset = self.offset;
+
+        self.offset += self.length as u64;
+        self.line += self.record.iter().filter(|byte| **byte == b'\n').count() as u64;
+        if !self.record.ends_with(b"\n") && !self.record.is_empty() {
+            self.line += 1;
+        }
+
+        let buffer = std::mem::take(&mut self.record);
+        let truncated = self.length > buffer.len();
+        // Records only end inside a quoted field at the end of input
+        let unterminated = self.quoting == Quoting::Quoted;
+        self.length = 0;
+        self.quoting = Quoting::FieldStart;
+
+        let mut record = buffer.as_slice();
+        if self.columns.is_none() {
+            record = record.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(record);
+        }
+        record = record.strip_suffix(b"\n").unwrap_or(record);
+        record = record.strip_suffix(b"\r").unwrap_or(record);
+
+        let io_error = |source| events::NextError::IO {
+            line,
+            offset,
+            raw: String::from_utf8_lossy(record).into_owned(),
+            source,
+        };
+        if let Err(error) = result {
+            return Some(Err(io_error(error)));
+        }
+        if buffer.is_empty() {
+            return None;
+        }
+        let parse_error = |source: FromCsvRowError| events::NextError::Parse {
+            line,
+            offset,
+            raw: String::from_utf8_lossy(record).into_owned(),
+            source: source.into(),
+        };
+        if truncated {
+            return Some(Err(parse_error(FromCsvRowError::TooLong(
+                MAX_RECORD_LENGTH,
+            ))));
         }
+        if unterminated {
+            return Some(Err(parse_error(FromCsvRowError::UnterminatedQuote)));
+        }
+        let Ok(raw) = std::str::from_utf8(record) else {
+            return Some(Err(parse_error(FromCsvRowError::InvalidUtf8)));
+        };
+
+        Some(Ok(Record {
+            line,
+            offset,
+            raw: raw.to_string(),
+            fields: split_record(raw),
+        }))
+    }
+}
+
+impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> futures::Stream
+    for CsvEventsReader<R>
+{
+    type Item = Result<events::Event, events::NextError>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_event(cx)
+    }
+}
+
+impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> events::EventSource
+    for CsvEventsReader<R>
+{
+    fn next_event(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<events::Event, events::NextError>>> + Send
+    {
+        CsvEventsReader::next_event(self)
+    }
+}
+
+// Splits a record into fields, unquoting quoted fields. Whitespace around unquoted fields is trimmed.
+fn split_record(record: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut field = String::new();
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            // Anything between the closing quote and the delimiter is ignored
+            while chars.next_if(|c| *c != ',').is_some() {}
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+            field.truncate(field.trim_end().len());
+        }
+        fields.push(field);
+
+        if chars.next().is_none() {
+            return fields;
+        }
+    }
+}
+
+// Positions of known columns in a record, unknown columns are ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Columns {
+    event_type: usize,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    reason: Option<usize>,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self {
+            event_type: 0,
+            client: Some(1),
+            tx: Some(2),
+            amount: Some(3),
+            reason: Some(4),
+        }
+    }
+}
+
+impl Columns {
+    // Returns None if the record is not a header
+    fn from_header(fields: &[String]) -> Option<Self> {
+        let position = |name: &str| {
+            fields
+                .iter()
+                .position(|field| field.eq_ignore_ascii_case(name))
+        };
+        Some(Self {
+            event_type: position("type")?,
+            client: position("client"),
+            tx: position("tx"),
+            amount: position("amount"),
+            reason: position("reason"),
+        })
+    }
+}
+
+pub struct RejectedEventsCsvWriter<W: std::io::Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: std::io::Write> RejectedEventsCsvWriter<W> {
+    pub fn from_writer(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+        }
+    }
+
+    pub fn write_rejected(
+        &mut self,
+        event: &events::Event,
+        reason: events::RejectionReason,
+    ) -> std::io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "type,client,tx,amount,reason,rejection")?;
+            self.header_written = true;
+        }
+        writeln!(self.writer, "{},{}", to_csv_row(event), reason)
+    }
+}
+
+fn to_csv_row(event: &events::Event) -> String {
+    match event {
+        events::Event::Deposit(deposit) => format!(
+            "deposit,{},{},{},",
+            deposit.client_id, deposit.id, deposit.amount
+        ),
+        events::Event::Withdrawal(withdrawal) => format!(
+            "withdrawal,{},{},{},",
+            withdrawal.client_id, withdrawal.id, withdrawal.amount
+        ),
+        events::Event::Dispute(dispute) => {
+            format!("dispute,{},{},,", dispute.client_id, dispute.transaction_id)
+        }
+        events::Event::Resolve(resolve) => {
+            format!("resolve,{},{},,", resolve.client_id, resolve.transaction_id)
+        }
+        events::Event::Chargeback(chargeback) => format!(
+            "chargeback,{},{},,",
+            chargeback.client_id, chargeback.transaction_id
+        ),
+        events::Event::Unlock(unlock) => format!(
+            "unlock,{},,,{}",
+            unlock.client_id,
+            escape_field(&unlock.reason)
+        ),
+        events::Event::Freeze(freeze) => format!(
+            "freeze,{},,,{}",
+            freeze.client_id,
+            escape_field(&freeze.reason)
+        ),
+        events::Event::Close(close) => format!(
+            "close,{},,,{}",
+            close.client_id,
+            escape_field(&close.reason)
+        ),
+    }
+}
+
+// Quotes the field if it contains a delimiter, a quote or a line break
+fn escape_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+// Writes rows that could not be parsed as they were read, with an extra column for the error
+pub struct QuarantineCsvWriter<W: std::io::Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: std::io::Write> QuarantineCsvWriter<W> {
+    pub fn from_writer(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+        }
+    }
+
+    pub fn write_quarantined(
+        &mut self,
+        header: &str,
+        raw: &str,
+        error: &FromCsvRowError,
+    ) -> std::io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{},error", header)?;
+            self.header_written = true;
+        }
+        // Short rows are padded so that the error ends up in its own column
+        let padding = split_record(header)
+            .len()
+            .saturating_sub(split_record(raw).len());
+        writeln!(
+            self.writer,
+            "{}{},{}",
+            raw,
+            ",".repeat(padding),
+            escape_field(&error.to_string())
+        )
     }
 }
 
@@ -53,108 +478,109 @@ pub enum FromCsvRowError {
     MissingTransactionId,
     #[error("invalid amount: {0}")]
     InvalidAmount(String),
+    #[error("amount is not a finite number: {0}")]
+    NonFiniteAmount(String),
+    #[error("amount must be positive: {0}")]
+    NonPositiveAmount(String),
+    #[error("amount has more than four decimal places: {0}")]
+    TooPreciseAmount(String),
     #[error("missing amount")]
     MissingAmount,
+    #[error("missing reason")]
+    MissingReason,
+    #[error("record is longer than {0} bytes")]
+    TooLong(usize),
+    #[error("quoted field is not closed")]
+    UnterminatedQuote,
+    #[error("record is not valid UTF-8")]
+    InvalidUtf8,
 }
 
-fn from_csv_row(s: &str) -> Result<events::Event, FromCsvRowError> {
-    let mut parts = s.split(',');
-    let transaction_type = parts
-        .next()
-        .ok_or(FromCsvRowError::MissingTransactionType)?;
-    match transaction_type.trim() {
-        "deposit" => {
-            let client_id = parts.next().ok_or(FromCsvRowError::MissonClientId)?;
-            let client_id = client_id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))?;
-            let id = parts.next().ok_or(FromCsvRowError::MissingTransactionId)?;
-            let id = id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))?;
-            let amount = parts.next().ok_or(FromCsvRowError::MissingAmount)?;
-            let amount = amount
-                .trim()
-                .parse::<f64>()
-                .map_err(|_| FromCsvRowError::InvalidAmount(amount.to_string()))?;
-            Ok(events::Event::Transaction(events::Transaction {
-                id,
-                client_id,
-                amount,
-            }))
-        }
-        "withdrawal" => {
-            let client_id = parts.next().ok_or(FromCsvRowError::MissonClientId)?;
-            let client_id = client_id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))?;
-            let id = parts.next().ok_or(FromCsvRowError::MissingTransactionId)?;
-            let id = id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))?;
-            let amount = parts.next().ok_or(FromCsvRowError::MissingAmount)?;
-            let amount = amount
-                .trim()
-                .parse::<f64>()
-                .map_err(|_| FromCsvRowError::InvalidAmount(amount.to_string()))?;
-            Ok(events::Event::Transaction(events::Transaction {
-                id,
-                client_id,
-                amount: -amount,
-            }))
-        }
-        "dispute" => {
-            let client_id = parts.next().ok_or(FromCsvRowError::MissonClientId)?;
-            let client_id = client_id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))?;
-            let id = parts.next().ok_or(FromCsvRowError::MissingTransactionId)?;
-            let id = id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))?;
-            Ok(events::Event::Dispute(events::Dispute {
-                transaction_id: id,
-                client_id,
-            }))
-        }
-        "resolve" => {
-            let client_id = parts.next().ok_or(FromCsvRowError::MissonClientId)?;
-            let client_id = client_id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))?;
-            let id = parts.next().ok_or(FromCsvRowError::MissingTransactionId)?;
-            let id = id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))?;
-            Ok(events::Event::Resolve(events::Resolve {
-                transaction_id: id,
-                client_id,
-            }))
-        }
-        "chargeback" => {
-            let client_id = parts.next().ok_or(FromCsvRowError::MissonClientId)?;
-            let client_id = client_id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))?;
-            let id = parts.next().ok_or(FromCsvRowError::MissingTransactionId)?;
-            let id = id
-                .trim()
-                .parse()
-                .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))?;
-            Ok(events::Event::Chargeback(events::Chargeback {
-                transaction_id: id,
-                client_id,
-            }))
+// Parses fields in the default column order, other line based formats are validated the same way
+pub(super) fn from_fields(fields: &[String]) -> Result<events::Event, FromCsvRowError> {
+    from_csv_row(fields, &Columns::default())
+}
+
+fn from_csv_row(fields: &[String], columns: &Columns) -> Result<events::Event, FromCsvRowError> {
+    // Empty fields are treated the same way as missing ones
+    let field = |column: Option<usize>| {
+        column
+            .and_then(|column| fields.get(column))
+            .map(|field| field.trim())
+            .filter(|field| !field.is_empty())
+    };
+    let client_id = || -> Result<u16, FromCsvRowError> {
+        let client_id = field(columns.client).ok_or(FromCsvRowError::MissonClientId)?;
+        client_id
+            .parse()
+            .map_err(|_| FromCsvRowError::InvalidClientId(client_id.to_string()))
+    };
+    let transaction_id = || -> Result<u32, FromCsvRowError> {
+        let id = field(columns.tx).ok_or(FromCsvRowError::MissingTransactionId)?;
+        id.parse()
+            .map_err(|_| FromCsvRowError::InvalidTransactionId(id.to_string()))
+    };
+    let amount = || -> Result<Amount, FromCsvRowError> {
+        let raw = field(columns.amount).ok_or(FromCsvRowError::MissingAmount)?;
+        let amount = raw.parse::<Amount>().map_err(|error| match error {
+            crate::amount::ParseAmountError::TooPrecise => {
+                FromCsvRowError::TooPreciseAmount(raw.to_string())
+            }
+            // Amount only accepts digits, but "NaN" and "inf" deserve a clearer error
+            _ if raw.parse::<f64>().is_ok_and(|amount| !amount.is_finite()) => {
+                FromCsvRowError::NonFiniteAmount(raw.to_string())
+            }
+            _ => FromCsvRowError::InvalidAmount(raw.to_string()),
+        })?;
+        if amount <= Amount::ZERO {
+            return Err(FromCsvRowError::NonPositiveAmount(raw.to_string()));
         }
+        Ok(amount)
+    };
+    let reason = || -> Result<String, FromCsvRowError> {
+        field(columns.reason)
+            .map(str::to_string)
+            .ok_or(FromCsvRowError::MissingReason)
+    };
+
+    let transaction_type =
+        field(Some(columns.event_type)).ok_or(FromCsvRowError::MissingTransactionType)?;
+    match transaction_type {
+        "deposit" => Ok(events::Event::Deposit(events::Deposit {
+            client_id: client_id()?,
+            id: transaction_id()?,
+            amount: amount()?,
+        })),
+        "withdrawal" => Ok(events::Event::Withdrawal(events::Withdrawal {
+            client_id: client_id()?,
+            id: transaction_id()?,
+            amount: amount()?,
+        })),
+        "dispute" => Ok(events::Event::Dispute(events::Dispute {
+            client_id: client_id()?,
+            transaction_id: transaction_id()?,
+        })),
+        "resolve" => Ok(events::Event::Resolve(events::Resolve {
+            client_id: client_id()?,
+            transaction_id: transaction_id()?,
+        })),
+        "chargeback" => Ok(events::Event::Chargeback(events::Chargeback {
+            client_id: client_id()?,
+            transaction_id: transaction_id()?,
+        })),
+        // Administrative events do not reference a transaction and have no amount
+        "unlock" => Ok(events::Event::Unlock(events::Unlock {
+            client_id: client_id()?,
+            reason: reason()?,
+        })),
+        "freeze" => Ok(events::Event::Freeze(events::Freeze {
+            client_id: client_id()?,
+            reason: reason()?,
+        })),
+        "close" => Ok(events::Event::Close(events::Close {
+            client_id: client_id()?,
+            reason: reason()?,
+        })),
         _ => Err(FromCsvRowError::InvalidEventType(
             transaction_type.to_string(),
         )),
@@ -0,0 +1,205 @@
+use std::io::{BufRead, Seek, Write};
+
+use crate::events;
+
+// Every entry is a single line of `<sequence>,<checksum>,<event as JSON>`, where the checksum is
+// CRC32 of `<sequence>,<event as JSON>`.
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub sequence: u64,
+    pub event: events::Event,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to access journal: {0}")]
+    IO(std::io::Error),
+    #[error("corrupted journal entry at byte {offset}")]
+    Corrupted { offset: u64 },
+    #[error("journal entry {sequence} at byte {offset} is out of order")]
+    OutOfOrder { sequence: u64, offset: u64 },
+    #[error("journal entries {first} to {last} do not continue the state at entry {sequence}")]
+    Discontinuous {
+        first: u64,
+        last: u64,
+        sequence: u64,
+    },
+}
+
+pub struct Journal<W: Write> {
+    writer: W,
+    sequence: u64,
+    // Set after a failed write, which may have left a torn entry that later entries must not be appended to
+    failed: bool,
+}
+
+impl<W: Write> Journal<W> {
+    // Sequence is the number of the last entry already written to the writer
+    pub fn new(writer: W, sequence: u64) -> Self {
+        Self {
+            writer,
+            sequence,
+            failed: false,
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn append(&mut self, event: &events::Event) -> std::io::Result<u64> {
+        if self.failed {
+            return Err(std::io::Error::other(
+                "journal is not writable after an earlier write error",
+            ));
+        }
+        let sequence = self.sequence + 1;
+        let payload = serde_json::to_string(event)?;
+        let checksum = checksum(sequence, &payload);
+        // Written with a single call, so that a crash or a failed write can only leave a torn entry at the end
+        let written = self
+            .writer
+            .write_all(format!("{},{:08x},{}\n", sequence, checksum, payload).as_bytes())
+            .and_then(|()| self.writer.flush());
+        if let Err(error) = written {
+            self.failed = true;
+            return Err(error);
+        }
+        self.sequence = sequence;
+        Ok(sequence)
+    }
+}
+
+impl Journal<std::fs::File> {
+    // Opens the journal for appending, replaying every existing entry through the callback.
+    // A torn entry left at the end by a crash is truncated. Sequence is the last entry included in the
+    // restored state: the journal must continue it, and new entries are numbered after it even if the
+    // journal has been rotated since.
+    pub fn recover(
+        path: impl AsRef<std::path::Path>,
+        sequence: u64,
+        mut replay: impl FnMut(Entry),
+    ) -> Result<Self, Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(Error::IO)?;
+
+        let mut reader = Reader::new(std::io::BufReader::new(&file));
+        let mut first = None;
+        for entry in &mut reader {
+            let entry = entry?;
+            first.get_or_insert(entry.sequence);
+            replay(entry);
+        }
+        let (offset, last) = (reader.offset(), reader.sequence());
+
+        // Entries missing between the state and the journal, or a journal that ends before the state, mean
+        // that the journal belongs to another state
+        if let Some(first) = first {
+            if first > sequence + 1 || last < sequence {
+                return Err(Error::Discontinuous {
+                    first,
+                    last,
+                    sequence,
+                });
+            }
+        }
+
+        file.set_len(offset).map_err(Error::IO)?;
+        file.seek(std::io::SeekFrom::End(0)).map_err(Error::IO)?;
+        Ok(Self::new(file, last.max(sequence)))
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.writer.sync_data()
+    }
+}
+
+pub struct Reader<R: BufRead> {
+    reader: R,
+    line: String,
+    // Byte offset right after the last valid entry
+    offset: u64,
+    // The first entry may have any sequence, a rotated journal starts after the state it was rotated at
+    sequence: u64,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            offset: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn next_entry(&mut self) -> Option<Result<Entry, Error>> {
+        self.line.clear();
+        let length = match self.reader.read_line(&mut self.line) {
+            Ok(0) => return None,
+            Ok(length) => length,
+            Err(error) => return Some(Err(Error::IO(error))),
+        };
+
+        let Some(line) = self.line.strip_suffix('\n') else {
+            // Only the last entry can be missing its newline, it was torn by a crash while being written
+            return None;
+        };
+
+        let Some((sequence, event)) = decode(line) else {
+            return Some(Err(Error::Corrupted {
+                offset: self.offset,
+            }));
+        };
+        if self.offset > 0 && sequence != self.sequence + 1 {
+            return Some(Err(Error::OutOfOrder {
+                sequence,
+                offset: self.offset,
+            }));
+        }
+
+        self.offset += length as u64;
+        self.sequence = sequence;
+        Some(Ok(Entry { sequence, event }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+    }
+}
+
+fn checksum(sequence: u64, payload: &str) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(format!("{},", sequence).as_bytes());
+    hasher.update(payload.as_bytes());
+    hasher.finalize()
+}
+
+fn decode(line: &str) -> Option<(u64, events::Event)> {
+    let mut parts = line.splitn(3, ',');
+    let sequence = parts.next()?.parse().ok()?;
+    let expected_checksum = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let payload = parts.next()?;
+    if checksum(sequence, payload) != expected_checksum {
+        return None;
+    }
+    let event = serde_json::from_str(payload).ok()?;
+    Some((sequence, event))
+}
@@ -0,0 +1,164 @@
+use tokio::io::AsyncBufReadExt;
+
+use crate::events;
+
+// Reads one JSON object per line, in the same format as events are serialized
+pub struct JsonlEventsReader<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> {
+    reader: tokio::io::BufReader<R>,
+    buffer: Vec<u8>,
+    // Number of lines and bytes consumed so far
+    line: u64,
+    offset: u64,
+    // Set after a read error, the input is not read any further
+    failed: bool,
+}
+
+impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> JsonlEventsReader<R> {
+    pub fn from_reader(reader: R) -> Self {
+        const BUFFER_SIZE: usize = 8 * 1024;
+        let reader = tokio::io::BufReader::with_capacity(BUFFER_SIZE, reader);
+        Self {
+            reader,
+            buffer: Vec::new(),
+            line: 0,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    // Reads a part of an input that starts at a line boundary at the given offset, line numbers are counted from the
+    // start of the part
+    pub fn from_range(reader: R, offset: u64) -> Self {
+        Self {
+            offset,
+            ..Self::from_reader(reader)
+        }
+    }
+
+    // Number of lines read so far
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    pub async fn next_event(&mut self) -> Option<Result<events::Event, events::NextError>> {
+        while !self.failed {
+            let line = self.line + 1;
+            let offset = self.offset;
+
+            self.buffer.clear();
+            let result = self.reader.read_until(b'\n', &mut self.buffer).await;
+            self.line += 1;
+            self.offset += self.buffer.len() as u64;
+
+            let mut raw = self.buffer.as_slice();
+            if offset == 0 {
+                raw = raw.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(raw);
+            }
+            raw = raw.strip_suffix(b"\n").unwrap_or(raw);
+            raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+
+            let mut io_error = |source| {
+                self.failed = true;
+                events::NextError::IO {
+                    line,
+                    offset,
+                    raw: String::from_utf8_lossy(raw).into_owned(),
+                    source,
+                }
+            };
+            match result {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(error) => return Some(Err(io_error(error))),
+            }
+            let Ok(raw) = std::str::from_utf8(raw) else {
+                return Some(Err(events::NextError::Parse {
+                    line,
+                    offset,
+                    raw: String::from_utf8_lossy(raw).into_owned(),
+                    source: FromJsonLineError::InvalidUtf8.into(),
+                }));
+            };
+
+            if raw.trim().is_empty() {
+                // Skip blank lines
+                continue;
+            }
+
+            return Some(
+                from_json_line(raw).map_err(|source| events::NextError::Parse {
+                    line,
+                    offset,
+                    raw: raw.to_string(),
+                    source: source.into(),
+                }),
+            );
+        }
+        None
+    }
+}
+
+impl<R: tokio::io::AsyncRead + std::marker::Unpin + Send + 'static> events::EventSource
+    for JsonlEventsReader<R>
+{
+    fn next_event(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<events::Event, events::NextError>>> + Send
+    {
+        JsonlEventsReader::next_event(self)
+    }
+}
+
+// Writes lines that could not be parsed with an added error field, lines that are not JSON objects are
+// wrapped into one
+pub struct QuarantineJsonlWriter<W: std::io::Write> {
+    writer: W,
+}
+
+impl<W: std::io::Write> QuarantineJsonlWriter<W> {
+    pub fn from_writer(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn write_quarantined(
+        &mut self,
+        raw: &str,
+        error: &FromJsonLineError,
+    ) -> std::io::Result<()> {
+        let mut object = match serde_json::from_str::<serde_json::Value>(raw) {
+            Ok(serde_json::Value::Object(object)) => object,
+            _ => serde_json::Map::from_iter([("raw".to_string(), raw.into())]),
+        };
+        object.insert("error".to_string(), error.to_string().into());
+        serde_json::to_writer(&mut self.writer, &object)?;
+        writeln!(self.writer)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FromJsonLineError {
+    #[error("invalid JSON: {0}")]
+    InvalidJson(serde_json::Error),
+    #[error("line is not a JSON object")]
+    NotAnObject,
+    #[error(transparent)]
+    InvalidEvent(#[from] events::csv::FromCsvRowError),
+    #[error("line is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+fn from_json_line(s: &str) -> Result<events::Event, FromJsonLineError> {
+    let serde_json::Value::Object(object) =
+        serde_json::from_str(s).map_err(FromJsonLineError::InvalidJson)?
+    else {
+        return Err(FromJsonLineError::NotAnObject);
+    };
+    // Fields are validated the same way as CSV columns, so both formats report the same errors. Numbers are accepted
+    // wherever strings are.
+    let fields = ["type", "client", "tx", "amount", "reason"].map(|name| match object.get(name) {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+    });
+    Ok(events::csv::from_fields(&fields)?)
+}
@@ -0,0 +1,43 @@
+use std::io::BufRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+
+// Splits an input into ranges of about `size` bytes that can be read independently. Every range but the first starts
+// right after a line break, so that line based readers can start reading from it.
+pub fn split(
+    input: &mut (impl std::io::Read + std::io::Seek),
+    size: u64,
+) -> std::io::Result<Vec<std::ops::Range<u64>>> {
+    let length = input.seek(std::io::SeekFrom::End(0))?;
+    let size = size.max(1);
+
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut line = Vec::new();
+    while start < length {
+        let end = start + size;
+        if end >= length {
+            ranges.push(start..length);
+            break;
+        }
+
+        // Extend the range up to the end of the line it ends in
+        input.seek(std::io::SeekFrom::Start(end - 1))?;
+        line.clear();
+        let read = std::io::BufReader::new(&mut *input).read_until(b'\n', &mut line)?;
+        let end = end - 1 + read as u64;
+        ranges.push(start..end);
+        start = end;
+    }
+    Ok(ranges)
+}
+
+// Opens a file for reading of a single range
+pub async fn open(
+    path: impl AsRef<std::path::Path>,
+    range: std::ops::Range<u64>,
+) -> std::io::Result<tokio::io::Take<tokio::fs::File>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(range.start)).await?;
+    Ok(file.take(range.end - range.start))
+}
@@ -0,0 +1,123 @@
+use crate::accounts;
+use crate::amount::Amount;
+use crate::events;
+
+// Bumped on every incompatible change of the snapshot format
+pub const VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    // Sequence number of the last journal entry included in the snapshot, added in version 2
+    #[serde(default)]
+    pub sequence: u64,
+    pub clients: Vec<Client>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Client {
+    pub account: accounts::Account,
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Transaction {
+    pub id: u32,
+    // Added in version 3
+    #[serde(default)]
+    pub kind: events::TransactionKind,
+    pub amount: Amount,
+    pub state: events::TransactionState,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to access snapshot: {0}")]
+    IO(std::io::Error),
+    #[error("failed to parse snapshot: {0}")]
+    Parse(serde_json::Error),
+    #[error("unsupported snapshot version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("balance of client {0} overflows when migrating the snapshot")]
+    Overflow(u16),
+}
+
+impl Snapshot {
+    pub fn new(clients: Vec<Client>) -> Self {
+        Self {
+            version: VERSION,
+            sequence: 0,
+            clients,
+        }
+    }
+
+    pub fn from_reader(reader: impl std::io::Read) -> Result<Self, Error> {
+        let mut snapshot: Self = serde_json::from_reader(reader).map_err(Error::Parse)?;
+        if !(1..=VERSION).contains(&snapshot.version) {
+            return Err(Error::UnsupportedVersion(snapshot.version));
+        }
+        // Before version 3 withdrawals were stored with negative amounts
+        if snapshot.version < 3 {
+            for client in &mut snapshot.clients {
+                for transaction in &mut client.transactions {
+                    let (true, Ok(amount)) = (
+                        transaction.amount.is_negative(),
+                        transaction.amount.checked_neg(),
+                    ) else {
+                        continue;
+                    };
+                    transaction.kind = events::TransactionKind::Withdrawal;
+                    transaction.amount = amount;
+
+                    // A disputed withdrawal used to move its amount from held to available, now the amount is held
+                    // on top of the total and available does not change
+                    if transaction.state == events::TransactionState::Disputed {
+                        let account = &mut client.account;
+                        let (Ok(available), Ok(held), Ok(total)) = (
+                            account.available.checked_sub(amount),
+                            amount
+                                .checked_add(amount)
+                                .and_then(|twice| account.held.checked_add(twice)),
+                            account.total.checked_add(amount),
+                        ) else {
+                            return Err(Error::Overflow(account.client));
+                        };
+                        account.available = available;
+                        account.held = held;
+                        account.total = total;
+                    }
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    pub fn to_writer(&self, writer: impl std::io::Write) -> Result<(), Error> {
+        serde_json::to_writer(writer, self).map_err(Error::Parse)
+    }
+
+    // Returns None if there is no snapshot at the path yet
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Option<Self>, Error> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::from_reader(std::io::BufReader::new(file)).map(Some),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(Error::IO(error)),
+        }
+    }
+
+    // Writes to a temporary file first, so that a crash never leaves a partially written snapshot
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let file = std::fs::File::create(&tmp_path).map_err(Error::IO)?;
+        let mut writer = std::io::BufWriter::new(file);
+        self.to_writer(&mut writer)?;
+        let file = writer
+            .into_inner()
+            .map_err(|error| Error::IO(error.into_error()))?;
+        file.sync_all().map_err(Error::IO)?;
+        std::fs::rename(&tmp_path, path).map_err(Error::IO)
+    }
+}
@@ -1,2 +1,5 @@
 pub mod accounts;
+pub mod amount;
 pub mod events;
+pub mod pipeline;
+pub mod policy;
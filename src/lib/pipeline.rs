@@ -0,0 +1,496 @@
+use crate::accounts;
+use crate::events;
+use crate::policy;
+
+pub type SharedJournal = std::sync::Arc<std::sync::Mutex<events::journal::Journal<std::fs::File>>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Read(#[from] events::NextError),
+    #[error("failed to append to journal: {0}")]
+    Journal(std::io::Error),
+    // The reason is returned by `ShardedProcessor::finish`
+    #[error("worker has stopped")]
+    Stopped,
+}
+
+pub struct Config {
+    // Number of messages that can be queued for every worker
+    pub capacity: usize,
+    // Accepted events are appended to the journal before they are applied
+    pub journal: Option<SharedJournal>,
+    // Every processed event is sent here together with its outcome
+    pub outcomes: Option<tokio::sync::mpsc::Sender<(events::Event, events::Outcome)>>,
+    // Clients are assigned to shards by their ID modulo the number of shards when not set
+    pub assignment: Option<Assignment>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            journal: None,
+            outcomes: None,
+            assignment: None,
+        }
+    }
+}
+
+// Maps every client to the shard that processes its events. A client is owned by exactly one shard, so its
+// events are always applied in order, and can only be moved to another shard between runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    shards: usize,
+    table: std::sync::Arc<[u16]>,
+}
+
+impl Assignment {
+    pub fn modulo(shards: usize) -> Self {
+        Self::from_fn(shards, |client_id| client_id as usize % shards)
+    }
+
+    // Spreads clients over shards so that every shard gets a similar share of the load. Busiest clients are
+    // placed first, each on the shard with the least load so far. Clients without load keep their modulo shard.
+    pub fn balanced(shards: usize, load: &Load) -> Self {
+        let mut clients = load.events.iter().collect::<Vec<_>>();
+        clients.sort_by_key(|(client_id, events)| (std::cmp::Reverse(**events), **client_id));
+
+        let mut shard_load = vec![0; shards];
+        let mut assigned = std::collections::HashMap::with_capacity(clients.len());
+        for (client_id, events) in clients {
+            let (_, shard) = shard_load
+                .iter()
+                .enumerate()
+                .map(|(shard, load)| (*load, shard))
+                .min()
+                .expect("there is at least one shard");
+            shard_load[shard] += events;
+            assigned.insert(*client_id, shard);
+        }
+
+        Self::from_fn(shards, |client_id| {
+            assigned
+                .get(&client_id)
+                .copied()
+                .unwrap_or(client_id as usize % shards)
+        })
+    }
+
+    fn from_fn(shards: usize, shard: impl Fn(u16) -> usize) -> Self {
+        assert!(
+            (1..=u16::MAX as usize + 1).contains(&shards),
+            "invalid number of shards: {}",
+            shards
+        );
+        Self {
+            shards,
+            table: (0..=u16::MAX)
+                .map(|client_id| shard(client_id) as u16)
+                .collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    // Returns the index of the shard that owns the client
+    pub fn shard(&self, client_id: u16) -> usize {
+        self.table[client_id as usize] as usize
+    }
+}
+
+// Number of events per client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Load {
+    events: std::collections::BTreeMap<u16, u64>,
+}
+
+impl Load {
+    // Estimates the load from the number of transactions every client has made so far
+    pub fn from_snapshot(snapshot: &events::snapshot::Snapshot) -> Self {
+        let mut load = Self::default();
+        for client in &snapshot.clients {
+            load.add(client.account.client, client.transactions.len() as u64);
+        }
+        load
+    }
+
+    pub fn add(&mut self, client_id: u16, events: u64) {
+        if events > 0 {
+            *self.events.entry(client_id).or_default() += events;
+        }
+    }
+
+    pub fn client(&self, client_id: u16) -> u64 {
+        self.events.get(&client_id).copied().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.events.values().sum()
+    }
+
+    // Returns the number of events every shard gets with the given assignment
+    pub fn shards(&self, assignment: &Assignment) -> Vec<u64> {
+        let mut shards = vec![0; assignment.shards()];
+        for (client_id, events) in &self.events {
+            shards[assignment.shard(*client_id)] += events;
+        }
+        shards
+    }
+
+    // Returns how much busier the busiest shard is than the average one: 1.0 when the load is spread evenly,
+    // up to the number of shards when all of it ends up on a single shard
+    pub fn imbalance(&self, assignment: &Assignment) -> f64 {
+        let shards = self.shards(assignment);
+        let total = shards.iter().sum::<u64>();
+        if total == 0 {
+            return 1.0;
+        }
+        let max = shards.iter().max().copied().unwrap_or_default();
+        max as f64 * shards.len() as f64 / total as f64
+    }
+}
+
+// Splits the snapshot between engines, so that every client ends up in the engine of its shard
+pub fn restore(
+    engines: &mut [events::Engine],
+    assignment: &Assignment,
+    snapshot: events::snapshot::Snapshot,
+) {
+    let mut shards = engines
+        .iter()
+        .map(|_| events::snapshot::Snapshot {
+            sequence: snapshot.sequence,
+            ..events::snapshot::Snapshot::new(Vec::new())
+        })
+        .collect::<Vec<_>>();
+    for client in snapshot.clients {
+        shards[assignment.shard(client.account.client)]
+            .clients
+            .push(client);
+    }
+    for (engine, shard) in engines.iter_mut().zip(shards) {
+        engine.restore(shard);
+    }
+}
+
+// Moves clients between engines according to a new assignment, for example between two input files
+pub fn redistribute(engines: Vec<events::Engine>, assignment: &Assignment) -> Vec<events::Engine> {
+    let Some(policy) = engines.first().map(|engine| engine.policy().clone()) else {
+        return engines;
+    };
+    let snapshot = events::snapshot::Snapshot {
+        sequence: engines
+            .iter()
+            .map(|engine| engine.sequence())
+            .max()
+            .unwrap_or_default(),
+        ..events::snapshot::Snapshot::new(
+            engines
+                .iter()
+                .flat_map(|engine| engine.snapshot().clients)
+                .collect(),
+        )
+    };
+    let mut engines = (0..assignment.shards())
+        .map(|_| events::Engine::with_policy(policy.clone()))
+        .collect::<Vec<_>>();
+    restore(&mut engines, assignment, snapshot);
+    engines
+}
+
+// Merges accounts of all engines, each ordered by client, into a single sequence ordered by client
+pub fn merge(engines: Vec<events::Engine>) -> impl Iterator<Item = accounts::Account> {
+    let mut shards = engines
+        .into_iter()
+        .map(|engine| engine.into_accounts().peekable())
+        .collect::<Vec<_>>();
+    std::iter::from_fn(move || {
+        let (_, shard) = shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, shard)| shard.peek().map(|account| (account.client, index)))
+            .min()?;
+        shards[shard].next()
+    })
+}
+
+enum Message {
+    Event(
+        events::Event,
+        Option<tokio::sync::oneshot::Sender<events::Outcome>>,
+    ),
+    Account(u16, tokio::sync::oneshot::Sender<Option<accounts::Account>>),
+    Accounts {
+        after: Option<u16>,
+        limit: usize,
+        reply: tokio::sync::oneshot::Sender<Vec<accounts::Account>>,
+    },
+}
+
+// Routes events to workers making sure that events from the same client are processed by the same worker
+#[derive(Clone)]
+pub struct Router {
+    senders: Vec<tokio::sync::mpsc::Sender<Message>>,
+    assignment: Assignment,
+}
+
+impl Router {
+    fn sender(&self, client_id: u16) -> &tokio::sync::mpsc::Sender<Message> {
+        &self.senders[self.assignment.shard(client_id)]
+    }
+
+    pub fn assignment(&self) -> &Assignment {
+        &self.assignment
+    }
+
+    // Fails if the worker of the client has stopped after an error
+    pub async fn route(&self, event: events::Event) -> Result<(), Error> {
+        self.sender(event.client_id())
+            .send(Message::Event(event, None))
+            .await
+            .map_err(|_| Error::Stopped)
+    }
+
+    // Routes the event and waits for it to be processed
+    pub async fn submit(&self, event: events::Event) -> Result<events::Outcome, Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender(event.client_id())
+            .send(Message::Event(event, Some(tx)))
+            .await
+            .map_err(|_| Error::Stopped)?;
+        rx.await.map_err(|_| Error::Stopped)
+    }
+
+    pub async fn account(&self, client_id: u16) -> Result<Option<accounts::Account>, Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender(client_id)
+            .send(Message::Account(client_id, tx))
+            .await
+            .map_err(|_| Error::Stopped)?;
+        rx.await.map_err(|_| Error::Stopped)
+    }
+
+    // Returns up to `limit` accounts ordered by client ID, starting after the given client
+    pub async fn accounts(
+        &self,
+        after: Option<u16>,
+        limit: usize,
+    ) -> Result<Vec<accounts::Account>, Error> {
+        let mut replies = Vec::with_capacity(self.senders.len());
+        for sender in &self.senders {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            sender
+                .send(Message::Accounts {
+                    after,
+                    limit,
+                    reply: tx,
+                })
+                .await
+                .map_err(|_| Error::Stopped)?;
+            replies.push(rx);
+        }
+
+        let mut accounts = futures::future::try_join_all(replies)
+            .await
+            .map_err(|_| Error::Stopped)?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        accounts.sort_by_key(|account| account.client);
+        accounts.truncate(limit);
+        Ok(accounts)
+    }
+}
+
+// Applies events to an engine, journaling them and reporting their outcomes as configured
+struct Worker {
+    engine: events::Engine,
+    journal: Option<SharedJournal>,
+    outcomes: Option<tokio::sync::mpsc::Sender<(events::Event, events::Outcome)>>,
+}
+
+impl Worker {
+    async fn apply(&mut self, event: events::Event) -> std::io::Result<events::Outcome> {
+        let processed_event = self.outcomes.as_ref().map(|_| event.clone());
+        let outcome = match &self.journal {
+            Some(journal) => self
+                .engine
+                .apply_journaled(event, &mut journal.lock().expect("journal is not poisoned"))?,
+            None => self.engine.apply(event),
+        };
+        if let (Some(outcomes), Some(event)) = (&self.outcomes, processed_event) {
+            // Outcomes are dropped if nobody listens to them anymore
+            let _ = outcomes.send((event, outcome)).await;
+        }
+        Ok(outcome)
+    }
+}
+
+// Pool of workers, each owning an engine for its share of clients
+pub struct ShardedProcessor {
+    router: Router,
+    handles: Vec<tokio::task::JoinHandle<std::io::Result<events::Engine>>>,
+}
+
+impl ShardedProcessor {
+    pub fn new(policy: &policy::Policy, workers: usize, capacity: usize) -> Self {
+        let engines = (0..workers)
+            .map(|_| events::Engine::with_policy(policy.clone()))
+            .collect();
+        Self::start(
+            engines,
+            Config {
+                capacity,
+                ..Default::default()
+            },
+        )
+    }
+
+    // Starts a worker for every engine. The engine at index i must only hold clients of shard i,
+    // see `restore`.
+    pub fn start(engines: Vec<events::Engine>, config: Config) -> Self {
+        let assignment = config
+            .assignment
+            .clone()
+            .unwrap_or_else(|| Assignment::modulo(engines.len()));
+        assert_eq!(
+            assignment.shards(),
+            engines.len(),
+            "assignment must have a shard for every engine"
+        );
+
+        let mut senders = Vec::with_capacity(engines.len());
+        let mut handles = Vec::with_capacity(engines.len());
+        for engine in engines {
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(config.capacity);
+            let mut worker = Worker {
+                engine,
+                journal: config.journal.clone(),
+                outcomes: config.outcomes.clone(),
+            };
+            senders.push(tx);
+            handles.push(tokio::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    let (event, reply) = match message {
+                        Message::Event(event, reply) => (event, reply),
+                        Message::Account(client_id, reply) => {
+                            let _ = reply.send(worker.engine.account(client_id).cloned());
+                            continue;
+                        }
+                        Message::Accounts {
+                            after,
+                            limit,
+                            reply,
+                        } => {
+                            let accounts = worker
+                                .engine
+                                .accounts()
+                                .filter(|account| after.is_none_or(|after| account.client > after))
+                                .take(limit)
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            let _ = reply.send(accounts);
+                            continue;
+                        }
+                    };
+
+                    // The worker stops if the event can not be journaled, dropping its queue
+                    let outcome = worker.apply(event).await?;
+                    if let Some(reply) = reply {
+                        let _ = reply.send(outcome);
+                    }
+                }
+                Ok(worker.engine)
+            }));
+        }
+
+        Self {
+            router: Router {
+                senders,
+                assignment,
+            },
+            handles,
+        }
+    }
+
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    pub async fn route(&self, event: events::Event) -> Result<(), Error> {
+        self.router.route(event).await
+    }
+
+    // Routes every event of the source, stopping at the first event that could not be read or routed
+    pub async fn process(&self, source: &mut impl events::EventSource) -> Result<(), Error> {
+        while let Some(event) = source.next_event().await {
+            self.route(event?).await?;
+        }
+        Ok(())
+    }
+
+    // Processes the whole source and returns the resulting accounts ordered by client
+    pub async fn run(
+        self,
+        source: &mut impl events::EventSource,
+    ) -> Result<Vec<accounts::Account>, Error> {
+        let processed = self.process(source).await;
+        // A worker that has stopped tells why
+        let engines = self.finish().await?;
+        processed?;
+        Ok(merge(engines).collect())
+    }
+
+    // Waits for all routed events to be processed and returns the engines in shard order, or the error
+    // that stopped a worker. All router clones must be dropped before calling this.
+    pub async fn finish(self) -> Result<Vec<events::Engine>, Error> {
+        // Drop the senders to signal workers to stop
+        drop(self.router);
+
+        futures::future::try_join_all(self.handles)
+            .await
+            .expect("all workers are running")
+            .into_iter()
+            .map(|engine| engine.map_err(Error::Journal))
+            .collect()
+    }
+}
+
+// Applies events to a single engine on the calling task, without a router or any queues in between. Capacity and
+// assignment of the config are not used.
+pub struct InlineProcessor {
+    worker: Worker,
+}
+
+impl InlineProcessor {
+    pub fn start(engine: events::Engine, config: Config) -> Self {
+        Self {
+            worker: Worker {
+                engine,
+                journal: config.journal,
+                outcomes: config.outcomes,
+            },
+        }
+    }
+
+    // Fails if the event could not be journaled, by this or by an earlier call
+    pub async fn route(&mut self, event: events::Event) -> Result<(), Error> {
+        self.worker.apply(event).await.map_err(Error::Journal)?;
+        Ok(())
+    }
+
+    // Routes every event of the source, stopping at the first event that could not be read or journaled
+    pub async fn process(&mut self, source: &mut impl events::EventSource) -> Result<(), Error> {
+        while let Some(event) = source.next_event().await {
+            self.route(event?).await?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> events::Engine {
+        self.worker.engine
+    }
+}
@@ -0,0 +1,63 @@
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub lock_on: LockOn,
+    pub disputable_withdrawals: bool,
+    pub negative_available_on_dispute: bool,
+    pub locked_accounts: LockedAccounts,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            lock_on: LockOn::NegativeBalance,
+            disputable_withdrawals: true,
+            negative_available_on_dispute: true,
+            locked_accounts: LockedAccounts::RejectAll,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockOn {
+    // Lock the account instead of applying a chargeback that would make the total negative
+    #[default]
+    NegativeBalance,
+    // Apply every chargeback and lock the account afterwards
+    AnyChargeback,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedAccounts {
+    #[default]
+    RejectAll,
+    // Deposits are still credited, everything else is rejected
+    AcceptDeposits,
+    // Disputes, resolves and chargebacks are still processed, transactions are rejected
+    AcceptDisputes,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("failed to read policy: {0}")]
+    IO(std::io::Error),
+    #[error("failed to parse policy: {0}")]
+    Parse(toml::de::Error),
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, LoadError> {
+        let contents = std::fs::read_to_string(path).map_err(LoadError::IO)?;
+        contents.parse()
+    }
+}
+
+impl std::str::FromStr for Policy {
+    type Err = LoadError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(LoadError::Parse)
+    }
+}
@@ -1,61 +1,268 @@
-use tinypay::accounts;
+mod http;
+mod input;
+mod output;
+mod serve;
+mod workers;
+
+use clap::Parser;
 use tinypay::events;
+use tinypay::events::EventSource;
+use tinypay::pipeline;
 
-#[tokio::main]
-async fn main() {
-    let Some(input) = std::env::args().nth(1) else {
-        eprintln!("Usage: {} <input>", std::env::args().next().unwrap());
-        std::process::exit(1);
-    };
+#[derive(Parser)]
+#[command(
+    about = "Process a CSV or JSON Lines file of transactions into account balances",
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// CSV or JSON Lines file with transactions
+    #[arg(required = true)]
+    input: Option<std::path::PathBuf>,
+    /// Format of the input file, detected by extension when not set
+    #[arg(long, value_enum)]
+    input_format: Option<input::Format>,
+    /// Print a summary of malformed rows to stderr after processing
+    #[arg(long)]
+    error_summary: bool,
+    /// Stop with a non-zero exit code on the first malformed row, read errors always stop processing
+    #[arg(long, conflicts_with = "max_errors")]
+    strict: bool,
+    /// Stop with a non-zero exit code once more than this many rows are malformed
+    #[arg(long)]
+    max_errors: Option<usize>,
+    /// Write rows that could not be parsed, with an added error column, to this file in the input format
+    #[arg(long)]
+    quarantine: Option<std::path::PathBuf>,
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Accept events over TCP and HTTP and keep accounts in memory, accounts are written on Ctrl-C
+    #[command(group = clap::ArgGroup::new("listeners").required(true).multiple(true))]
+    Serve {
+        /// Address to accept CSV event lines on
+        #[arg(long, group = "listeners")]
+        listen: Option<std::net::SocketAddr>,
+        /// Address to serve the HTTP JSON API on
+        #[arg(long, group = "listeners")]
+        http: Option<std::net::SocketAddr>,
+        #[command(flatten)]
+        options: Options,
+    },
+}
+
+#[derive(clap::Args)]
+struct Options {
+    /// Write rejected events with their rejection reasons to this CSV file
+    #[arg(long)]
+    rejected: Option<std::path::PathBuf>,
+    /// TOML file with business rules, defaults are used when not set
+    #[arg(long)]
+    policy: Option<std::path::PathBuf>,
+    /// Snapshot file to resume from and to save the state to after processing
+    #[arg(long)]
+    state: Option<std::path::PathBuf>,
+    /// Journal file to recover from and to append accepted events to
+    #[arg(long)]
+    journal: Option<std::path::PathBuf>,
+    /// Format of the account balances written to stdout
+    #[arg(long, value_enum, default_value_t = output::Format::Csv)]
+    output_format: output::Format,
+    /// Order of the account balances, ties are ordered by client
+    #[arg(long, value_enum, default_value_t = output::SortKey::Client)]
+    sort: output::SortKey,
+    /// Number of workers processing events, defaults to the number of logical CPUs. With 1 worker a file is
+    /// processed on a single thread
+    #[arg(long, env = "TINYPAY_WORKERS")]
+    workers: Option<std::num::NonZeroUsize>,
+    /// Number of events that can be queued for every worker before reading input is paused
+    #[arg(long, env = "TINYPAY_QUEUE_DEPTH", default_value = "1024")]
+    queue_depth: std::num::NonZeroUsize,
+}
+
+impl Options {
+    fn workers(&self) -> usize {
+        self.workers
+            .map_or_else(num_cpus::get, std::num::NonZeroUsize::get)
+    }
+}
+
+impl Args {
+    fn options(&self) -> &Options {
+        match &self.command {
+            Some(Command::Serve { options, .. }) => options,
+            None => &self.options,
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
 
-    let Ok(file) = tokio::fs::File::open(&input).await else {
-        eprintln!("Failed to open input file: {}", input);
-        std::process::exit(1);
+    // The runtime is sized to the number of workers, so that CPU quotas are respected
+    let workers = args.options().workers();
+    let mut runtime = if workers == 1 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(workers);
+        builder
     };
+    runtime
+        .enable_all()
+        .build()
+        .expect("failed to start runtime")
+        .block_on(run(args));
+}
+
+async fn run(args: Args) {
+    match args.command {
+        Some(Command::Serve {
+            listen,
+            http,
+            options,
+        }) => {
+            let workers = workers::Workers::start(&options, false);
 
-    let mut events_reader = events::csv::CsvEventsReader::from_reader(file);
-
-    // Initialize the number of workers to the number of logical CPUs
-    let workers_count = num_cpus::get();
-    let mut senders = Vec::with_capacity(workers_count);
-    let mut handles = Vec::with_capacity(workers_count);
-    for _ in 0..workers_count {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
-        senders.push(tx);
-        handles.push(tokio::spawn(async move {
-            let mut events = Vec::new();
-            while let Some(event) = rx.recv().await {
-                events.push(event);
+            // Both servers stop on Ctrl-C
+            let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+            tokio::spawn(async move {
+                let _ = tokio::signal::ctrl_c().await;
+                let _ = shutdown_tx.send(());
+            });
+            let shutdown = move || {
+                let mut shutdown_rx = shutdown_rx.clone();
+                async move {
+                    let _ = shutdown_rx.changed().await;
+                }
+            };
+
+            let mut servers = tokio::task::JoinSet::new();
+            if let Some(address) = listen {
+                let listener = bind(address, "TCP").await;
+                servers.spawn(serve::serve(listener, workers.router(), shutdown()));
+            }
+            if let Some(address) = http {
+                let listener = bind(address, "HTTP").await;
+                servers.spawn(http::serve(listener, workers.router(), shutdown()));
+            }
+            while servers.join_next().await.is_some() {}
+
+            workers.finish(&options).await;
+        }
+        None => {
+            let input = args.input.expect("input is required without a command");
+            let format = args
+                .input_format
+                .unwrap_or_else(|| input::Format::from_path(&input));
+            // Large files are parsed by as many tasks as there are workers
+            let Ok(mut events_reader) = input::Reader::open(format, &input, args.options.workers())
+            else {
+                eprintln!("Failed to open input file: {}", input.display());
+                std::process::exit(1);
+            };
+
+            let mut workers = workers::Workers::start(&args.options, args.options.workers() == 1);
+            let max_errors = if args.strict {
+                Some(0)
+            } else {
+                args.max_errors
+            };
+            let mut quarantine = args
+                .quarantine
+                .as_ref()
+                .map(|path| input::Quarantine::create(format, path));
+            let mut summary = ErrorSummary::default();
+            while let Some(event) = events_reader.next_event().await {
+                let error = match event {
+                    Ok(event) => match workers.route(event).await {
+                        Ok(()) => continue,
+                        // A stopped worker reports why when finishing
+                        Err(pipeline::Error::Stopped) => break,
+                        Err(error) => {
+                            eprintln!("Failed to process {}: {}", input.display(), error);
+                            std::process::exit(1);
+                        }
+                    },
+                    Err(error) => error,
+                };
+
+                if let (Some(quarantine), events::NextError::Parse { raw, source, .. }) =
+                    (&mut quarantine, &error)
+                {
+                    quarantine
+                        .write(&events_reader, raw, source)
+                        .expect("failed to write quarantined row");
+                }
+
+                // Read errors may hide the rest of the input, so they always stop processing
+                let io_error = matches!(error, events::NextError::IO { .. });
+                if io_error || max_errors.is_some_and(|max| summary.count >= max) {
+                    drop(quarantine);
+                    if args.error_summary {
+                        summary.print();
+                    }
+                    eprintln!("Failed to process {}: {}", input.display(), error);
+                    std::process::exit(1);
+                }
+                summary.add(error);
+            }
+            drop(quarantine);
+            workers.finish(&args.options).await;
+
+            if args.error_summary {
+                summary.print();
             }
-            events::reduce(&events)
-        }));
+        }
     }
+}
 
-    // Distribute events to workers making sure that events from the same client are processed by the same worker
-    while let Some(event) = events_reader.next_event().await {
-        let Ok(event) = event else { continue };
-        let worker_index = event.client_id() as usize % workers_count;
-        senders[worker_index]
-            .send(event)
-            .await
-            .expect("receiver is not dropped");
+async fn bind(address: std::net::SocketAddr, protocol: &str) -> tokio::net::TcpListener {
+    let listener = match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Failed to listen on {}: {}", address, error);
+            std::process::exit(1);
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        eprintln!("Listening for {} on {}", protocol, address);
     }
+    listener
+}
 
-    // Drop the senders to signal workers to stop
-    for tx in senders {
-        drop(tx);
+// Keeps the first few errors and counts the rest, so that a broken file does not flood stderr
+#[derive(Default)]
+struct ErrorSummary {
+    errors: Vec<events::NextError>,
+    count: usize,
+}
+
+impl ErrorSummary {
+    const LIMIT: usize = 10;
+
+    fn add(&mut self, error: events::NextError) {
+        if self.errors.len() < Self::LIMIT {
+            self.errors.push(error);
+        }
+        self.count += 1;
     }
 
-    let mut accounts_writer = accounts::csv::AccountsCsvWriter::from_writer(std::io::stdout());
-
-    futures::future::try_join_all(handles)
-        .await
-        .expect("all workers are running")
-        .into_iter()
-        .flatten()
-        .for_each(|account| {
-            accounts_writer
-                .write_account(&account)
-                .expect("failed to write account")
-        });
+    fn print(&self) {
+        if self.count == 0 {
+            return;
+        }
+        eprintln!("{} malformed rows:", self.count);
+        for error in &self.errors {
+            eprintln!("  {}", error);
+        }
+        if self.count > self.errors.len() {
+            eprintln!("  ... and {} more", self.count - self.errors.len());
+        }
+    }
 }
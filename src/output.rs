@@ -0,0 +1,45 @@
+use tinypay::accounts;
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Csv,
+    /// A single JSON array of accounts
+    Json,
+    /// One JSON object per account per line
+    Ndjson,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+// Orders accounts that are already sorted by client, accounts with equal keys stay ordered by client
+pub fn sort(
+    accounts: impl Iterator<Item = accounts::Account> + 'static,
+    key: SortKey,
+) -> Box<dyn Iterator<Item = accounts::Account>> {
+    let amount = match key {
+        SortKey::Client => return Box::new(accounts),
+        SortKey::Available => |account: &accounts::Account| account.available,
+        SortKey::Held => |account: &accounts::Account| account.held,
+        SortKey::Total => |account: &accounts::Account| account.total,
+    };
+    let mut accounts = accounts.collect::<Vec<_>>();
+    accounts.sort_by_key(amount);
+    Box::new(accounts.into_iter())
+}
+
+pub fn sink<W: std::io::Write + 'static>(
+    format: Format,
+    writer: W,
+) -> Box<dyn accounts::AccountSink> {
+    match format {
+        Format::Csv => Box::new(accounts::csv::AccountsCsvWriter::from_writer(writer)),
+        Format::Json => Box::new(accounts::json::AccountsJsonWriter::from_writer(writer)),
+        Format::Ndjson => Box::new(accounts::json::AccountsNdjsonWriter::from_writer(writer)),
+    }
+}
@@ -0,0 +1,43 @@
+use tinypay::events;
+use tinypay::pipeline;
+
+// Accepts connections until shutdown, every connection is a stream of CSV event lines
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    router: pipeline::Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let mut connections = tokio::task::JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(handle_connection(stream, router.clone()));
+                }
+                Err(error) => eprintln!("Failed to accept connection: {}", error),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Events that were not routed yet are dropped together with the connections
+    connections.shutdown().await;
+}
+
+async fn handle_connection(stream: tokio::net::TcpStream, router: pipeline::Router) {
+    let mut events_reader = events::csv::CsvEventsReader::from_reader(stream);
+    while let Some(event) = events_reader.next_event().await {
+        match event {
+            Ok(event) => {
+                if router.route(event).await.is_err() {
+                    // The worker has stopped, the reason is reported when the server shuts down
+                    break;
+                }
+            }
+            Err(events::NextError::Parse { .. }) => continue,
+            Err(events::NextError::IO { .. }) => break,
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use tinypay::events;
+use tinypay::pipeline;
+use tinypay::policy;
+
+// Busiest worker may get this many times the average load before clients are rebalanced
+const MAX_IMBALANCE: f64 = 1.5;
+
+type RejectedWriter = events::csv::RejectedEventsCsvWriter<std::io::BufWriter<std::fs::File>>;
+
+// Pool of workers, each owning an engine for its share of clients
+pub struct Workers {
+    pool: Pool,
+    journal: Option<pipeline::SharedJournal>,
+    rejected_handle: Option<tokio::task::JoinHandle<()>>,
+}
+
+enum Pool {
+    Inline(pipeline::InlineProcessor),
+    Sharded(pipeline::ShardedProcessor),
+}
+
+impl Workers {
+    // With a single worker and no need for a router, events are processed inline
+    pub fn start(options: &crate::Options, inline: bool) -> Self {
+        let policy = match &options.policy {
+            Some(path) => match policy::Policy::load(path) {
+                Ok(policy) => policy,
+                Err(error) => {
+                    eprintln!("Failed to load policy {}: {}", path.display(), error);
+                    std::process::exit(1);
+                }
+            },
+            None => policy::Policy::default(),
+        };
+
+        let rejected_writer = options.rejected.as_ref().map(|path| {
+            let Ok(file) = std::fs::File::create(path) else {
+                eprintln!("Failed to create rejected events file: {}", path.display());
+                std::process::exit(1);
+            };
+            events::csv::RejectedEventsCsvWriter::from_writer(std::io::BufWriter::new(file))
+        });
+
+        let workers_count = if inline { 1 } else { options.workers() };
+        let mut engines = (0..workers_count)
+            .map(|_| events::Engine::with_policy(policy.clone()))
+            .collect::<Vec<_>>();
+
+        let snapshot =
+            options
+                .state
+                .as_ref()
+                .and_then(|path| match events::snapshot::Snapshot::load(path) {
+                    Ok(snapshot) => snapshot,
+                    Err(error) => {
+                        eprintln!("Failed to load state {}: {}", path.display(), error);
+                        std::process::exit(1);
+                    }
+                });
+
+        // Clients that were busy in previous runs are spread over workers when they would overload some of them
+        let mut assignment = pipeline::Assignment::modulo(workers_count);
+        if let Some(snapshot) = &snapshot {
+            let load = pipeline::Load::from_snapshot(snapshot);
+            if load.imbalance(&assignment) > MAX_IMBALANCE {
+                assignment = pipeline::Assignment::balanced(workers_count, &load);
+            }
+        }
+
+        // Restore the previous state, making sure that every client ends up on the worker that will process its events
+        let sequence = snapshot.as_ref().map_or(0, |snapshot| snapshot.sequence);
+        if let Some(snapshot) = snapshot {
+            pipeline::restore(&mut engines, &assignment, snapshot);
+        }
+
+        // Replay journal entries that are not part of the restored state yet
+        let journal = match &options.journal {
+            Some(path) => {
+                let journal = events::journal::Journal::recover(path, sequence, |entry| {
+                    engines[assignment.shard(entry.event.client_id())].replay(entry);
+                });
+                match journal {
+                    Ok(journal) => Some(std::sync::Arc::new(std::sync::Mutex::new(journal))),
+                    Err(error) => {
+                        eprintln!("Failed to recover journal {}: {}", path.display(), error);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // Rejected events are written by a dedicated task so that workers are never blocked on I/O
+        let (outcomes, rejected_handle) = match rejected_writer {
+            Some(mut rejected_writer) => {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(options.queue_depth.get());
+                let handle = tokio::task::spawn_blocking(move || {
+                    while let Some((event, outcome)) = rx.blocking_recv() {
+                        write_rejected(&mut rejected_writer, &event, outcome);
+                    }
+                });
+                (Some(tx), Some(handle))
+            }
+            None => (None, None),
+        };
+        let config = pipeline::Config {
+            capacity: options.queue_depth.get(),
+            journal: journal.clone(),
+            outcomes,
+            assignment: Some(assignment),
+        };
+        let pool = if inline {
+            Pool::Inline(pipeline::InlineProcessor::start(
+                engines.pop().expect("there is one engine"),
+                config,
+            ))
+        } else {
+            Pool::Sharded(pipeline::ShardedProcessor::start(engines, config))
+        };
+
+        Self {
+            pool,
+            journal,
+            rejected_handle,
+        }
+    }
+
+    // Fails if the event could not be journaled, by this or by an earlier call
+    pub async fn route(&mut self, event: events::Event) -> Result<(), pipeline::Error> {
+        match &mut self.pool {
+            Pool::Inline(processor) => processor.route(event).await,
+            Pool::Sharded(processor) => processor.route(event).await,
+        }
+    }
+
+    pub fn router(&self) -> pipeline::Router {
+        match &self.pool {
+            Pool::Inline(_) => unreachable!("inline workers have no router"),
+            Pool::Sharded(processor) => processor.router(),
+        }
+    }
+
+    // Waits for all routed events to be processed, saves the state and writes accounts to stdout.
+    // All router clones must be dropped before calling this.
+    pub async fn finish(self, options: &crate::Options) {
+        let engines = match self.pool {
+            Pool::Inline(processor) => vec![processor.finish()],
+            Pool::Sharded(processor) => match processor.finish().await {
+                Ok(engines) => engines,
+                Err(error) => {
+                    eprintln!("Failed to process events: {}", error);
+                    std::process::exit(1);
+                }
+            },
+        };
+
+        if let Some(journal) = &self.journal {
+            if let Err(error) = journal.lock().expect("journal is not poisoned").sync() {
+                eprintln!("Failed to sync journal: {}", error);
+                std::process::exit(1);
+            }
+        }
+
+        if let Some(path) = &options.state {
+            // Every journal entry up to the highest sequence seen by any worker has been applied
+            let snapshot = events::snapshot::Snapshot {
+                sequence: engines
+                    .iter()
+                    .map(|engine| engine.sequence())
+                    .max()
+                    .unwrap_or_default(),
+                ..events::snapshot::Snapshot::new(
+                    engines
+                        .iter()
+                        .flat_map(|engine| engine.snapshot().clients)
+                        .collect(),
+                )
+            };
+            if let Err(error) = snapshot.save(path) {
+                eprintln!("Failed to save state {}: {}", path.display(), error);
+                std::process::exit(1);
+            }
+        }
+
+        let mut accounts_writer = crate::output::sink(options.output_format, std::io::stdout());
+
+        crate::output::sort(pipeline::merge(engines), options.sort).for_each(|account| {
+            accounts_writer
+                .write_account(&account)
+                .expect("failed to write account")
+        });
+        accounts_writer.finish().expect("failed to write accounts");
+
+        if let Some(handle) = self.rejected_handle {
+            handle.await.expect("rejected events writer is running");
+        }
+    }
+}
+
+fn write_rejected(
+    rejected_writer: &mut RejectedWriter,
+    event: &events::Event,
+    outcome: events::Outcome,
+) {
+    if let events::Outcome::Rejected(reason) = outcome {
+        rejected_writer
+            .write_rejected(event, reason)
+            .expect("failed to write rejected event");
+    }
+}
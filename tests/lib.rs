@@ -1,4 +1,1536 @@
+mod amount {
+    #[test]
+    fn parse() {
+        let amount: tinypay::amount::Amount = "1.5".parse().unwrap();
+        assert_eq!(amount.minor_units(), 15_000);
+
+        let amount: tinypay::amount::Amount = "-0.0001".parse().unwrap();
+        assert_eq!(amount.minor_units(), -1);
+
+        let amount: tinypay::amount::Amount = "2.50000".parse().unwrap();
+        assert_eq!(amount.minor_units(), 25_000);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            "".parse::<tinypay::amount::Amount>(),
+            Err(tinypay::amount::ParseAmountError::Empty)
+        );
+        assert_eq!(
+            "NaN".parse::<tinypay::amount::Amount>(),
+            Err(tinypay::amount::ParseAmountError::InvalidDigit)
+        );
+        assert_eq!(
+            "0.00001".parse::<tinypay::amount::Amount>(),
+            Err(tinypay::amount::ParseAmountError::TooPrecise)
+        );
+        assert_eq!(
+            "922337203685478".parse::<tinypay::amount::Amount>(),
+            Err(tinypay::amount::ParseAmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn display() {
+        let amount: tinypay::amount::Amount = "1.5".parse().unwrap();
+        assert_eq!(amount.to_string(), "1.5000");
+
+        let amount: tinypay::amount::Amount = "-0.0001".parse().unwrap();
+        assert_eq!(amount.to_string(), "-0.0001");
+    }
+
+    #[test]
+    fn overflow() {
+        let max = tinypay::amount::Amount::from_minor_units(i64::MAX);
+        let one: tinypay::amount::Amount = "1".parse().unwrap();
+        assert_eq!(max.checked_add(one), Err(tinypay::amount::OverflowError));
+        assert_eq!(
+            tinypay::amount::Amount::from_minor_units(i64::MIN).checked_sub(one),
+            Err(tinypay::amount::OverflowError)
+        );
+    }
+
+    #[test]
+    fn no_drift() {
+        let tenth: tinypay::amount::Amount = "0.1".parse().unwrap();
+        let mut sum = tinypay::amount::Amount::ZERO;
+        for _ in 0..10_000 {
+            sum = sum.checked_add(tenth).unwrap();
+        }
+        assert_eq!(sum, "1000".parse().unwrap());
+    }
+}
+
+mod accounts {
+    // Writers of every format can be used through the same trait object
+    #[test]
+    fn sink() {
+        let mut csv = Vec::new();
+        let mut json = Vec::new();
+        let mut sinks: Vec<Box<dyn tinypay::accounts::AccountSink>> = vec![
+            Box::new(tinypay::accounts::csv::AccountsCsvWriter::from_writer(
+                &mut csv,
+            )),
+            Box::new(tinypay::accounts::json::AccountsJsonWriter::from_writer(
+                &mut json,
+            )),
+        ];
+        for sink in &mut sinks {
+            sink.write_account(&tinypay::accounts::Account::new(1))
+                .unwrap();
+            sink.finish().unwrap();
+        }
+        drop(sinks);
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+        );
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[\n{\"client\":1,\"available\":\"0.0000\",\"held\":\"0.0000\",\"total\":\"0.0000\",\"locked\":false}\n]\n"
+        );
+    }
+
+    mod json {
+        fn account(client: u16) -> tinypay::accounts::Account {
+            let mut account = tinypay::accounts::Account::new(client);
+            account.available = "1.5".parse().unwrap();
+            account.held = "0.25".parse().unwrap();
+            account.total = "1.75".parse().unwrap();
+            account.closed = true;
+            account
+        }
+
+        #[test]
+        fn array() {
+            let mut output = Vec::new();
+            let mut writer = tinypay::accounts::json::AccountsJsonWriter::from_writer(&mut output);
+            writer.write_account(&account(1)).unwrap();
+            writer.write_account(&account(2)).unwrap();
+            writer.finish().unwrap();
+
+            assert_eq!(
+                serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+                serde_json::json!([
+                    {"client": 1, "available": "1.5000", "held": "0.2500", "total": "1.7500", "locked": false},
+                    {"client": 2, "available": "1.5000", "held": "0.2500", "total": "1.7500", "locked": false},
+                ])
+            );
+        }
+
+        #[test]
+        fn empty_array() {
+            let mut output = Vec::new();
+            let mut writer = tinypay::accounts::json::AccountsJsonWriter::from_writer(&mut output);
+            writer.finish().unwrap();
+            assert_eq!(output, b"[]\n");
+        }
+
+        #[test]
+        fn ndjson() {
+            let mut output = Vec::new();
+            let mut writer =
+                tinypay::accounts::json::AccountsNdjsonWriter::from_writer(&mut output);
+            writer.write_account(&account(1)).unwrap();
+            writer.write_account(&account(2)).unwrap();
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.2500\",\"total\":\"1.7500\",\"locked\":false}\n\
+                 {\"client\":2,\"available\":\"1.5000\",\"held\":\"0.2500\",\"total\":\"1.7500\",\"locked\":false}\n"
+            );
+        }
+    }
+}
+
 mod events {
+    // Events of client 1 shared by tests of every module
+    pub(crate) fn deposit(id: u32, amount: &str) -> tinypay::events::Event {
+        tinypay::events::Event::Deposit(tinypay::events::Deposit {
+            id,
+            client_id: 1,
+            amount: amount.parse().unwrap(),
+        })
+    }
+
+    pub(crate) fn withdrawal(id: u32, amount: &str) -> tinypay::events::Event {
+        tinypay::events::Event::Withdrawal(tinypay::events::Withdrawal {
+            id,
+            client_id: 1,
+            amount: amount.parse().unwrap(),
+        })
+    }
+
+    pub(crate) fn dispute(transaction_id: u32) -> tinypay::events::Event {
+        tinypay::events::Event::Dispute(tinypay::events::Dispute {
+            transaction_id,
+            client_id: 1,
+        })
+    }
+
+    pub(crate) fn resolve(transaction_id: u32) -> tinypay::events::Event {
+        tinypay::events::Event::Resolve(tinypay::events::Resolve {
+            transaction_id,
+            client_id: 1,
+        })
+    }
+
+    pub(crate) fn chargeback(transaction_id: u32) -> tinypay::events::Event {
+        tinypay::events::Event::Chargeback(tinypay::events::Chargeback {
+            transaction_id,
+            client_id: 1,
+        })
+    }
+
+    mod engine {
+        #[test]
+        fn empty() {
+            let engine = tinypay::events::Engine::new();
+            assert_eq!(engine.accounts().count(), 0);
+            assert!(engine.account(1).is_none());
+        }
+
+        #[test]
+        fn incremental() {
+            let mut engine = tinypay::events::Engine::new();
+
+            let outcome = engine.apply(crate::events::deposit(1, "100.0"));
+            assert_eq!(outcome, tinypay::events::Outcome::Applied);
+            assert_eq!(
+                engine.account(1).unwrap().available,
+                "100.0".parse().unwrap()
+            );
+
+            let outcome = engine.apply(crate::events::withdrawal(2, "150.0"));
+            assert_eq!(
+                outcome,
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::InsufficientFunds
+                )
+            );
+            assert_eq!(
+                engine.account(1).unwrap().available,
+                "100.0".parse().unwrap()
+            );
+
+            let outcome = engine.apply(tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                id: 3,
+                client_id: 2,
+                amount: "1.5".parse().unwrap(),
+            }));
+            assert_eq!(outcome, tinypay::events::Outcome::Applied);
+            assert_eq!(engine.accounts().count(), 2);
+        }
+
+        #[test]
+        fn rejection_reasons() {
+            let mut engine = tinypay::events::Engine::new();
+            engine.apply(crate::events::deposit(1, "100.0"));
+
+            assert_eq!(
+                engine.apply(crate::events::deposit(1, "100.0")),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::DuplicateTransaction
+                )
+            );
+            assert_eq!(
+                engine.apply(crate::events::dispute(2)),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::UnknownTransaction
+                )
+            );
+            assert_eq!(
+                engine.apply(crate::events::resolve(1)),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::NotDisputed)
+            );
+            assert_eq!(
+                engine.apply(tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                    id: 2,
+                    client_id: 1,
+                    amount: tinypay::amount::Amount::from_minor_units(i64::MAX),
+                },)),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::Overflow)
+            );
+        }
+
+        #[test]
+        fn ordered_accounts() {
+            let mut engine = tinypay::events::Engine::new();
+            for client_id in [300, 1, 20, 4000, 5] {
+                engine.apply(tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                    id: u32::from(client_id),
+                    client_id,
+                    amount: "1.0".parse().unwrap(),
+                }));
+            }
+
+            let client_ids = engine
+                .accounts()
+                .map(|account| account.client)
+                .collect::<Vec<_>>();
+            assert_eq!(client_ids, [1, 5, 20, 300, 4000]);
+            let client_ids = engine
+                .into_accounts()
+                .map(|account| account.client)
+                .collect::<Vec<_>>();
+            assert_eq!(client_ids, [1, 5, 20, 300, 4000]);
+        }
+
+        #[test]
+        fn non_positive_amount() {
+            let mut engine = tinypay::events::Engine::new();
+            assert_eq!(
+                engine.apply(crate::events::deposit(1, "-1.0")),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::NonPositiveAmount
+                )
+            );
+            assert_eq!(
+                engine.apply(crate::events::withdrawal(2, "0")),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::NonPositiveAmount
+                )
+            );
+        }
+
+        #[test]
+        fn withdrawal_dispute() {
+            let balances = |engine: &tinypay::events::Engine| {
+                let account = engine.account(1).unwrap();
+                (
+                    account.available.to_string(),
+                    account.held.to_string(),
+                    account.total.to_string(),
+                )
+            };
+
+            let mut engine = tinypay::events::Engine::new();
+            for event in [
+                crate::events::deposit(1, "100.0"),
+                crate::events::withdrawal(2, "30.0"),
+                crate::events::withdrawal(3, "20.0"),
+            ] {
+                assert_eq!(engine.apply(event), tinypay::events::Outcome::Applied);
+            }
+            assert_eq!(
+                balances(&engine),
+                ("50.0000".into(), "0.0000".into(), "50.0000".into())
+            );
+
+            // Disputed withdrawal is held, available funds do not change
+            assert_eq!(
+                engine.apply(crate::events::dispute(2)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                balances(&engine),
+                ("50.0000".into(), "30.0000".into(), "80.0000".into())
+            );
+
+            // Resolved withdrawal stands
+            assert_eq!(
+                engine.apply(crate::events::resolve(2)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                balances(&engine),
+                ("50.0000".into(), "0.0000".into(), "50.0000".into())
+            );
+
+            // Charged back withdrawal is refunded to available funds
+            assert_eq!(
+                engine.apply(crate::events::dispute(3)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                engine.apply(crate::events::chargeback(3)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                balances(&engine),
+                ("70.0000".into(), "0.0000".into(), "70.0000".into())
+            );
+            assert!(!engine.account(1).unwrap().locked);
+        }
+
+        #[test]
+        fn locked() {
+            let mut engine = tinypay::events::Engine::new();
+            for event in [
+                crate::events::deposit(1, "100.0"),
+                crate::events::withdrawal(2, "50.0"),
+                crate::events::dispute(1),
+            ] {
+                assert_eq!(engine.apply(event), tinypay::events::Outcome::Applied);
+            }
+
+            // The chargeback would make the total negative, the account is locked but the funds stay held
+            assert_eq!(
+                engine.apply(crate::events::chargeback(1)),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::InsufficientFunds
+                )
+            );
+            let account = engine.account(1).unwrap();
+            assert!(account.locked);
+            assert_eq!(account.held, "100.0".parse().unwrap());
+            assert_eq!(account.total, "50.0".parse().unwrap());
+
+            assert_eq!(
+                engine.apply(crate::events::deposit(3, "1.0")),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+        }
+    }
+
+    mod admin {
+
+        fn unlock() -> tinypay::events::Event {
+            tinypay::events::Event::Unlock(tinypay::events::Unlock {
+                client_id: 1,
+                reason: "investigated".to_string(),
+            })
+        }
+
+        fn freeze() -> tinypay::events::Event {
+            tinypay::events::Event::Freeze(tinypay::events::Freeze {
+                client_id: 1,
+                reason: "suspicious".to_string(),
+            })
+        }
+
+        fn close() -> tinypay::events::Event {
+            tinypay::events::Event::Close(tinypay::events::Close {
+                client_id: 1,
+                reason: "client request".to_string(),
+            })
+        }
+
+        #[test]
+        fn unlock_after_chargeback() {
+            let mut engine = tinypay::events::Engine::new();
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::withdrawal(2, "50.0"));
+            engine.apply(crate::events::dispute(1));
+            engine.apply(crate::events::chargeback(1));
+            assert!(engine.account(1).unwrap().locked);
+
+            assert_eq!(engine.apply(unlock()), tinypay::events::Outcome::Applied);
+            assert!(!engine.account(1).unwrap().locked);
+            assert_eq!(
+                engine.apply(crate::events::deposit(3, "100.0")),
+                tinypay::events::Outcome::Applied
+            );
+        }
+
+        #[test]
+        fn unlock_not_locked() {
+            let mut engine = tinypay::events::Engine::new();
+            assert_eq!(
+                engine.apply(unlock()),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::NotLocked)
+            );
+        }
+
+        #[test]
+        fn freeze_and_unlock() {
+            let mut engine = tinypay::events::Engine::new();
+            assert_eq!(engine.apply(freeze()), tinypay::events::Outcome::Applied);
+            assert!(engine.account(1).unwrap().locked);
+            assert_eq!(
+                engine.apply(crate::events::deposit(1, "100.0")),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+            assert_eq!(
+                engine.apply(freeze()),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+
+            assert_eq!(engine.apply(unlock()), tinypay::events::Outcome::Applied);
+            assert_eq!(
+                engine.apply(crate::events::deposit(1, "100.0")),
+                tinypay::events::Outcome::Applied
+            );
+        }
+
+        #[test]
+        fn close_is_final() {
+            let mut engine = tinypay::events::Engine::new();
+            engine.apply(crate::events::deposit(1, "100.0"));
+            assert_eq!(engine.apply(close()), tinypay::events::Outcome::Applied);
+
+            let account = engine.account(1).unwrap();
+            assert!(account.locked);
+            assert!(account.closed);
+
+            for event in [crate::events::deposit(2, "100.0"), unlock(), close()] {
+                assert_eq!(
+                    engine.apply(event),
+                    tinypay::events::Outcome::Rejected(
+                        tinypay::events::RejectionReason::AccountClosed
+                    )
+                );
+            }
+        }
+    }
+
+    mod snapshot {
+        #[test]
+        fn roundtrip() {
+            let mut engine = tinypay::events::Engine::new();
+            engine.apply(crate::events::deposit(1, "100.1234"));
+            engine.apply(crate::events::deposit(2, "10.0"));
+            engine.apply(crate::events::dispute(2));
+
+            let mut buffer = Vec::new();
+            engine.snapshot().to_writer(&mut buffer).unwrap();
+            let snapshot = tinypay::events::snapshot::Snapshot::from_reader(&buffer[..]).unwrap();
+            assert_eq!(snapshot.version, tinypay::events::snapshot::VERSION);
+
+            let mut restored = tinypay::events::Engine::new();
+            restored.restore(snapshot);
+            assert_eq!(restored.account(1), engine.account(1));
+            assert_eq!(
+                restored.transaction_state(1, 2),
+                Some(tinypay::events::TransactionState::Disputed)
+            );
+
+            // Disputes and resolves can reference transactions from previous runs
+            assert_eq!(
+                restored.apply(crate::events::resolve(2)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                restored.apply(crate::events::dispute(1)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                restored.account(1).unwrap().held,
+                "100.1234".parse().unwrap()
+            );
+        }
+
+        #[test]
+        fn unsupported_version() {
+            let result = tinypay::events::snapshot::Snapshot::from_reader(
+                &br#"{"version":0,"clients":[]}"#[..],
+            );
+            assert!(matches!(
+                result,
+                Err(tinypay::events::snapshot::Error::UnsupportedVersion(0))
+            ));
+        }
+
+        #[test]
+        fn negative_withdrawals() {
+            let snapshot = tinypay::events::snapshot::Snapshot::from_reader(
+                &br#"{"version":2,"sequence":0,"clients":[{
+                    "account":{"client":1,"available":"5","held":"0","total":"5","locked":false,"closed":false},
+                    "transactions":[
+                        {"id":1,"amount":"10","state":"settled"},
+                        {"id":2,"amount":"-5","state":"settled"}
+                    ]
+                }]}"#[..],
+            )
+            .unwrap();
+            let transactions = &snapshot.clients[0].transactions;
+            assert_eq!(
+                transactions[0].kind,
+                tinypay::events::TransactionKind::Deposit
+            );
+            assert_eq!(transactions[0].amount, "10".parse().unwrap());
+            assert_eq!(
+                transactions[1].kind,
+                tinypay::events::TransactionKind::Withdrawal
+            );
+            assert_eq!(transactions[1].amount, "5".parse().unwrap());
+        }
+
+        #[test]
+        fn disputed_negative_withdrawal() {
+            // Before version 3 the dispute of a withdrawal of 50 moved it from held to available
+            let snapshot = tinypay::events::snapshot::Snapshot::from_reader(
+                &br#"{"version":2,"sequence":0,"clients":[{
+                    "account":{"client":1,"available":"100","held":"-50","total":"50","locked":false,"closed":false},
+                    "transactions":[
+                        {"id":1,"amount":"100","state":"settled"},
+                        {"id":2,"amount":"-50","state":"disputed"}
+                    ]
+                }]}"#[..],
+            )
+            .unwrap();
+            let account = &snapshot.clients[0].account;
+            assert_eq!(account.available, "50".parse().unwrap());
+            assert_eq!(account.held, "50".parse().unwrap());
+            assert_eq!(account.total, "100".parse().unwrap());
+
+            let mut engine = tinypay::events::Engine::new();
+            engine.restore(snapshot);
+            assert_eq!(
+                engine.apply(crate::events::resolve(2)),
+                tinypay::events::Outcome::Applied
+            );
+            let account = engine.account(1).unwrap();
+            assert_eq!(account.available, "50".parse().unwrap());
+            assert_eq!(account.held, "0".parse().unwrap());
+            assert_eq!(account.total, "50".parse().unwrap());
+        }
+
+        #[test]
+        fn missing() {
+            let path = std::env::temp_dir().join("tinypay-missing-snapshot.json");
+            assert!(tinypay::events::snapshot::Snapshot::load(path)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    mod journal {
+        use std::io::Write;
+
+        #[test]
+        fn roundtrip() {
+            let mut buffer = Vec::new();
+            let mut journal = tinypay::events::journal::Journal::new(&mut buffer, 0);
+            assert_eq!(
+                journal.append(&crate::events::deposit(1, "1.5")).unwrap(),
+                1
+            );
+            assert_eq!(
+                journal.append(&crate::events::deposit(2, "2.5")).unwrap(),
+                2
+            );
+            assert_eq!(journal.sequence(), 2);
+
+            let entries = tinypay::events::journal::Reader::new(&buffer[..])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].sequence, 1);
+            assert_eq!(entries[1].sequence, 2);
+            let tinypay::events::Event::Deposit(transaction) = &entries[1].event else {
+                panic!("expected deposit");
+            };
+            assert_eq!(transaction.id, 2);
+            assert_eq!(transaction.amount, "2.5".parse().unwrap());
+        }
+
+        #[test]
+        fn corrupted() {
+            let mut buffer = Vec::new();
+            let mut journal = tinypay::events::journal::Journal::new(&mut buffer, 0);
+            journal.append(&crate::events::deposit(1, "1.5")).unwrap();
+            journal.append(&crate::events::deposit(2, "2.5")).unwrap();
+
+            // Change the amount of the first entry without updating the checksum
+            let buffer = String::from_utf8(buffer)
+                .unwrap()
+                .replacen("1.5000", "9.5000", 1);
+
+            let mut reader = tinypay::events::journal::Reader::new(buffer.as_bytes());
+            assert!(matches!(
+                reader.next_entry(),
+                Some(Err(tinypay::events::journal::Error::Corrupted {
+                    offset: 0
+                }))
+            ));
+        }
+
+        #[test]
+        fn torn() {
+            let mut buffer = Vec::new();
+            let mut journal = tinypay::events::journal::Journal::new(&mut buffer, 0);
+            journal.append(&crate::events::deposit(1, "1.5")).unwrap();
+            let valid_length = buffer.len() as u64;
+            buffer.extend_from_slice(b"2,0000");
+
+            let mut reader = tinypay::events::journal::Reader::new(&buffer[..]);
+            assert!(matches!(reader.next_entry(), Some(Ok(_))));
+            assert!(reader.next_entry().is_none());
+            assert_eq!(reader.offset(), valid_length);
+            assert_eq!(reader.sequence(), 1);
+        }
+
+        // Accepts writes up to the given length once, the write that crosses it is torn and fails
+        struct FailingWriter {
+            buffer: Vec<u8>,
+            fail_at: Option<usize>,
+        }
+
+        impl Write for FailingWriter {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                match self.fail_at.take() {
+                    Some(fail_at) if self.buffer.len() + bytes.len() > fail_at => {
+                        let length = fail_at.saturating_sub(self.buffer.len());
+                        self.buffer.extend_from_slice(&bytes[..length]);
+                        Err(std::io::Error::other("disk is full"))
+                    }
+                    fail_at => {
+                        self.fail_at = fail_at;
+                        self.buffer.extend_from_slice(bytes);
+                        Ok(bytes.len())
+                    }
+                }
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn failed_write() {
+            let mut writer = FailingWriter {
+                buffer: Vec::new(),
+                fail_at: Some(100),
+            };
+            let mut journal = tinypay::events::journal::Journal::new(&mut writer, 0);
+            assert_eq!(
+                journal.append(&crate::events::deposit(1, "1.5")).unwrap(),
+                1
+            );
+            assert!(journal.append(&crate::events::deposit(2, "2.5")).is_err());
+            // The writer works again, but appending after the torn entry would corrupt the journal
+            assert!(journal.append(&crate::events::deposit(3, "3.5")).is_err());
+            assert_eq!(journal.sequence(), 1);
+
+            assert_eq!(writer.buffer.len(), 100);
+            let entries = tinypay::events::journal::Reader::new(&writer.buffer[..])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(entries.len(), 1);
+        }
+
+        #[test]
+        fn recover() {
+            let path = std::env::temp_dir().join(format!(
+                "tinypay-journal-recover-{}.log",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+
+            let mut engine = tinypay::events::Engine::new();
+            let mut journal = tinypay::events::journal::Journal::recover(&path, 0, |entry| {
+                engine.replay(entry);
+            })
+            .unwrap();
+            assert_eq!(
+                engine
+                    .apply_journaled(crate::events::deposit(1, "10.0"), &mut journal)
+                    .unwrap(),
+                tinypay::events::Outcome::Applied
+            );
+            // Rejected events are not journaled
+            assert_eq!(
+                engine
+                    .apply_journaled(crate::events::deposit(1, "10.0"), &mut journal)
+                    .unwrap(),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::DuplicateTransaction
+                )
+            );
+            let snapshot = engine.snapshot();
+            assert_eq!(snapshot.sequence, 1);
+            engine
+                .apply_journaled(crate::events::deposit(2, "5.0"), &mut journal)
+                .unwrap();
+            drop(journal);
+
+            // Simulate a crash in the middle of writing an entry
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(b"3,12345678,{\"type\"")
+                .unwrap();
+
+            // Recover from the snapshot plus the tail of the journal
+            let mut recovered = tinypay::events::Engine::new();
+            recovered.restore(snapshot);
+            let mut replayed = Vec::new();
+            let mut journal = tinypay::events::journal::Journal::recover(&path, 1, |entry| {
+                replayed.push(entry.sequence);
+                recovered.replay(entry);
+            })
+            .unwrap();
+            assert_eq!(replayed, vec![1, 2]);
+            assert_eq!(recovered.sequence(), 2);
+            assert_eq!(recovered.account(1), engine.account(1));
+
+            // New entries continue the sequence after the truncated tail
+            assert_eq!(
+                journal.append(&crate::events::deposit(3, "1.0")).unwrap(),
+                3
+            );
+            drop(journal);
+            let entries = tinypay::events::journal::Reader::new(std::io::BufReader::new(
+                std::fs::File::open(&path).unwrap(),
+            ))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+            assert_eq!(entries.len(), 3);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn rotated() {
+            let path = std::env::temp_dir().join(format!(
+                "tinypay-journal-rotated-{}.log",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+
+            let mut engine = tinypay::events::Engine::new();
+            let mut journal = tinypay::events::journal::Journal::recover(&path, 0, |entry| {
+                engine.replay(entry);
+            })
+            .unwrap();
+            for id in 1..=3 {
+                engine
+                    .apply_journaled(crate::events::deposit(id, "1.0"), &mut journal)
+                    .unwrap();
+            }
+            drop(journal);
+            let snapshot = engine.snapshot();
+            assert_eq!(snapshot.sequence, 3);
+
+            // The journal is rotated after the state is saved, new entries continue the sequence of the state
+            std::fs::remove_file(&path).unwrap();
+            let mut journal = tinypay::events::journal::Journal::recover(&path, 3, |_| {
+                panic!("rotated journal is empty")
+            })
+            .unwrap();
+            assert_eq!(
+                engine
+                    .apply_journaled(crate::events::deposit(10, "100.0"), &mut journal)
+                    .unwrap(),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(journal.sequence(), 4);
+            drop(journal);
+
+            let mut recovered = tinypay::events::Engine::new();
+            recovered.restore(snapshot);
+            tinypay::events::journal::Journal::recover(&path, 3, |entry| {
+                assert!(recovered.replay(entry).is_some());
+            })
+            .unwrap();
+            assert_eq!(recovered.account(1), engine.account(1));
+            assert_eq!(
+                recovered.account(1).unwrap().total,
+                "103.0".parse().unwrap()
+            );
+
+            // A journal that does not continue the state is refused instead of being skipped
+            assert!(matches!(
+                tinypay::events::journal::Journal::recover(&path, 5, |_| {}),
+                Err(tinypay::events::journal::Error::Discontinuous {
+                    first: 4,
+                    last: 4,
+                    sequence: 5
+                })
+            ));
+            assert!(matches!(
+                tinypay::events::journal::Journal::recover(&path, 2, |_| {}),
+                Err(tinypay::events::journal::Error::Discontinuous { .. })
+            ));
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    mod csv {
+        #[tokio::test]
+        async fn read_error() {
+            // Reading a directory fails on every attempt, the reader stops after the first error
+            let directory = tokio::fs::File::open(std::env::temp_dir()).await.unwrap();
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(directory);
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::IO { .. }))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[test]
+        fn write_rejected() {
+            let mut output = Vec::new();
+            let mut writer =
+                tinypay::events::csv::RejectedEventsCsvWriter::from_writer(&mut output);
+            writer
+                .write_rejected(
+                    &crate::events::withdrawal(2, "1.5"),
+                    tinypay::events::RejectionReason::InsufficientFunds,
+                )
+                .unwrap();
+            writer
+                .write_rejected(
+                    &crate::events::dispute(3),
+                    tinypay::events::RejectionReason::UnknownTransaction,
+                )
+                .unwrap();
+            writer
+                .write_rejected(
+                    &tinypay::events::Event::Unlock(tinypay::events::Unlock {
+                        client_id: 1,
+                        reason: "investigated".to_string(),
+                    }),
+                    tinypay::events::RejectionReason::NotLocked,
+                )
+                .unwrap();
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "type,client,tx,amount,reason,rejection\n\
+                 withdrawal,1,2,1.5000,,insufficient_funds\n\
+                 dispute,1,3,,,unknown_transaction\n\
+                 unlock,1,,,investigated,not_locked\n"
+            );
+        }
+
+        #[tokio::test]
+        async fn read_admin() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"unlock, 1, , , chargeback investigated\n\
+                   freeze,2,,,suspicious activity\n\
+                   close,3,,,client request\n\
+                   unlock,1,,,\n"[..],
+            );
+
+            let Some(Ok(tinypay::events::Event::Unlock(unlock))) = reader.next_event().await else {
+                panic!("expected unlock event");
+            };
+            assert_eq!(unlock.client_id, 1);
+            assert_eq!(unlock.reason, "chargeback investigated");
+
+            let Some(Ok(tinypay::events::Event::Freeze(freeze))) = reader.next_event().await else {
+                panic!("expected freeze event");
+            };
+            assert_eq!(freeze.client_id, 2);
+            assert_eq!(freeze.reason, "suspicious activity");
+
+            let Some(Ok(tinypay::events::Event::Close(close))) = reader.next_event().await else {
+                panic!("expected close event");
+            };
+            assert_eq!(close.client_id, 3);
+            assert_eq!(close.reason, "client request");
+
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::MissingReason
+                    ),
+                    ..
+                }))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_header() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"\xEF\xBB\xBFamount,note,tx,client,type\r\n\
+                   1.5,first,1,2,deposit\r\n\
+                   \r\n\
+                   ,,1,2,dispute\r\n"[..],
+            );
+
+            let Some(Ok(tinypay::events::Event::Deposit(transaction))) = reader.next_event().await
+            else {
+                panic!("expected deposit event");
+            };
+            assert_eq!(transaction.id, 1);
+            assert_eq!(transaction.client_id, 2);
+            assert_eq!(transaction.amount, "1.5".parse().unwrap());
+
+            let Some(Ok(tinypay::events::Event::Dispute(dispute))) = reader.next_event().await
+            else {
+                panic!("expected dispute event");
+            };
+            assert_eq!(dispute.transaction_id, 1);
+            assert_eq!(dispute.client_id, 2);
+
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_quoted() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount,reason\n\
+                   \"deposit\",\"1\",\"1\",\"2.0\",\n\
+                   freeze,1,,,\"fraud, \"\"suspected\"\"\n see ticket\"\n\
+                   withdrawal,1,2,\"1,0\",\n"[..],
+            );
+
+            let Some(Ok(tinypay::events::Event::Deposit(transaction))) = reader.next_event().await
+            else {
+                panic!("expected deposit event");
+            };
+            assert_eq!(transaction.amount, "2".parse().unwrap());
+
+            let Some(Ok(tinypay::events::Event::Freeze(freeze))) = reader.next_event().await else {
+                panic!("expected freeze event");
+            };
+            assert_eq!(freeze.reason, "fraud, \"suspected\"\n see ticket");
+
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(tinypay::events::csv::FromCsvRowError::InvalidAmount(amount)),
+                    ..
+                })) if amount == "1,0"
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_stray_quote() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount\n\
+                   deposit,1,1,5\"\n\
+                   deposit,1,2,10\n\
+                   deposit,2,3,7\n"[..],
+            );
+
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert_eq!(error.line(), 2);
+            assert_eq!(error.raw(), "deposit,1,1,5\"");
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Ok(tinypay::events::Event::Deposit(_)))
+            ));
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Ok(tinypay::events::Event::Deposit(_)))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_quote_at_end() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount,reason\nfreeze,1,,,\"truncated\nreason"[..],
+            );
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    line: 2,
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::UnterminatedQuote
+                    ),
+                    ..
+                }))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_unterminated_quote() {
+            let mut input = b"type,client,tx,amount,reason\nfreeze,1,,,\"never closed\n".to_vec();
+            for tx in 1..=10_000 {
+                input.extend_from_slice(format!("deposit,2,{},1.0,\n", tx).as_bytes());
+            }
+            let mut reader =
+                tinypay::events::csv::CsvEventsReader::from_reader(std::io::Cursor::new(input));
+
+            // The quoted field gives up at the maximum record length, and reading continues after it
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert!(matches!(
+                error,
+                tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::TooLong(_)
+                    ),
+                    ..
+                }
+            ));
+            assert_eq!(error.line(), 2);
+            assert!(error.raw().len() <= 64 * 1024);
+
+            let mut deposits = 0;
+            while let Some(event) = reader.next_event().await {
+                assert!(matches!(event, Ok(tinypay::events::Event::Deposit(_))));
+                deposits += 1;
+            }
+            assert!(deposits > 5_000);
+            assert_eq!(reader.line(), 10_002);
+        }
+
+        #[tokio::test]
+        async fn read_long_line() {
+            let mut input = b"deposit,1,1,".to_vec();
+            input.extend(std::iter::repeat_n(b'1', 100 * 1024));
+            input.extend_from_slice(b"\ndeposit,1,2,1.0\n");
+            let mut reader =
+                tinypay::events::csv::CsvEventsReader::from_reader(std::io::Cursor::new(input));
+
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert_eq!(error.raw().len(), 64 * 1024);
+            let Some(Ok(tinypay::events::Event::Deposit(transaction))) = reader.next_event().await
+            else {
+                panic!("expected deposit event");
+            };
+            assert_eq!(transaction.id, 2);
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read_missing_column() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx\ndeposit,1,1\ndispute,1,1\n"[..],
+            );
+
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::MissingAmount
+                    ),
+                    ..
+                }))
+            ));
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Ok(tinypay::events::Event::Dispute(_)))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn stream() {
+            use futures::StreamExt;
+            use tokio::io::AsyncWriteExt;
+
+            // A small pipe makes the reader wait for more input in the middle of records
+            let (mut writer, reader) = tokio::io::duplex(4);
+            tokio::spawn(async move {
+                writer
+                    .write_all(
+                        b"type,client,tx,amount,reason\n\
+                          deposit,1,1,1.0,\n\
+                          freeze,2,,,\"multi\nline\"\n\
+                          deposit,3,x,1.0,\n\
+                          withdrawal,4,2,1.0,",
+                    )
+                    .await
+                    .unwrap();
+            });
+
+            let events = tinypay::events::csv::CsvEventsReader::from_reader(reader)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(events.len(), 4);
+            assert!(matches!(
+                &events[1],
+                Ok(tinypay::events::Event::Freeze(freeze)) if freeze.reason == "multi\nline"
+            ));
+            assert!(matches!(
+                &events[2],
+                Err(error) if error.line() == 5 && error.raw() == "deposit,3,x,1.0,"
+            ));
+            let client_ids = events
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|event| event.client_id())
+                .collect::<Vec<_>>();
+            assert_eq!(client_ids, [1, 2, 4]);
+        }
+
+        #[tokio::test]
+        async fn read_invalid_amounts() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount\n\
+                   deposit,1,1,NaN\n\
+                   deposit,1,2,-inf\n\
+                   deposit,1,3,-5\n\
+                   withdrawal,1,4,0\n\
+                   withdrawal,1,5,1.0000000001\n\
+                   deposit,1,6,1.5.0\n\
+                   deposit,1,7,1.00000\n"[..],
+            );
+
+            let mut errors = Vec::new();
+            while let Some(Err(tinypay::events::NextError::Parse { source, .. })) =
+                reader.next_event().await
+            {
+                errors.push(source.to_string());
+            }
+            assert_eq!(
+                errors,
+                [
+                    "amount is not a finite number: NaN",
+                    "amount is not a finite number: -inf",
+                    "amount must be positive: -5",
+                    "amount must be positive: 0",
+                    "amount has more than four decimal places: 1.0000000001",
+                    "invalid amount: 1.5.0",
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn read_error_position() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount,reason\n\
+                   deposit,1,1,1.0,\n\
+                   freeze,1,,,\"multi\nline\"\n\
+                   \n\
+                   deposit,1,2,abc,\n\
+                   deposit,1,3,\xFF,"[..],
+            );
+
+            assert!(matches!(reader.next_event().await, Some(Ok(_))));
+            assert!(matches!(reader.next_event().await, Some(Ok(_))));
+
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert!(matches!(
+                error,
+                tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::InvalidAmount(_)
+                    ),
+                    ..
+                }
+            ));
+            assert_eq!(error.line(), 6);
+            assert_eq!(error.offset(), 71);
+            assert_eq!(error.raw(), "deposit,1,2,abc,");
+            assert_eq!(
+                error.to_string(),
+                "line 6, offset 71: failed to parse event: invalid amount: abc: deposit,1,2,abc,"
+            );
+
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert!(matches!(
+                error,
+                tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Csv(
+                        tinypay::events::csv::FromCsvRowError::InvalidUtf8
+                    ),
+                    ..
+                }
+            ));
+            assert_eq!(error.line(), 7);
+            assert_eq!(error.offset(), 88);
+
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn write_quarantined() {
+            let mut reader = tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"tx,type,client,amount\n\
+                   1,deposit,1,abc\n\
+                   2,deposit\n"[..],
+            );
+            let mut output = Vec::new();
+            let mut writer = tinypay::events::csv::QuarantineCsvWriter::from_writer(&mut output);
+            while let Some(Err(tinypay::events::NextError::Parse {
+                raw,
+                source: tinypay::events::ParseError::Csv(source),
+                ..
+            })) = reader.next_event().await
+            {
+                writer
+                    .write_quarantined(reader.header(), &raw, &source)
+                    .unwrap();
+            }
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "tx,type,client,amount,error\n\
+                 1,deposit,1,abc,invalid amount: abc\n\
+                 2,deposit,,,missing client ID\n"
+            );
+        }
+
+        #[test]
+        fn write_rejected_quoted() {
+            let mut output = Vec::new();
+            let mut writer =
+                tinypay::events::csv::RejectedEventsCsvWriter::from_writer(&mut output);
+            writer
+                .write_rejected(
+                    &tinypay::events::Event::Freeze(tinypay::events::Freeze {
+                        client_id: 1,
+                        reason: "fraud, \"suspected\"".to_string(),
+                    }),
+                    tinypay::events::RejectionReason::AccountLocked,
+                )
+                .unwrap();
+
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "type,client,tx,amount,reason,rejection\n\
+                 freeze,1,,,\"fraud, \"\"suspected\"\"\",account_locked\n"
+            );
+        }
+    }
+
+    mod ranges {
+        const INPUT: &str = "type,client,tx,amount\n\
+                             deposit,1,1,1.0\n\
+                             deposit,2,2,2.0\n\
+                             deposit,3,3,abc\n\
+                             withdrawal,1,4,1.0\n\
+                             deposit,4,5,4.0\n";
+
+        #[test]
+        fn split() {
+            for size in 1..=INPUT.len() as u64 + 1 {
+                let ranges =
+                    tinypay::events::ranges::split(&mut std::io::Cursor::new(INPUT), size).unwrap();
+                assert_eq!(ranges.first().unwrap().start, 0);
+                assert_eq!(ranges.last().unwrap().end, INPUT.len() as u64);
+                for window in ranges.windows(2) {
+                    assert_eq!(window[0].end, window[1].start);
+                    assert_eq!(INPUT.as_bytes()[window[1].start as usize - 1], b'\n');
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn read() {
+            let first_line = INPUT.lines().next().unwrap();
+            let ranges =
+                tinypay::events::ranges::split(&mut std::io::Cursor::new(INPUT), 30).unwrap();
+            assert!(ranges.len() > 2);
+
+            let mut client_ids = Vec::new();
+            let mut lines = 0;
+            for range in ranges {
+                let part = &INPUT.as_bytes()[range.start as usize..range.end as usize];
+                let mut reader = if range.start == 0 {
+                    tinypay::events::csv::CsvEventsReader::from_reader(part)
+                } else {
+                    tinypay::events::csv::CsvEventsReader::from_range(part, first_line, range.start)
+                };
+                while let Some(event) = reader.next_event().await {
+                    match event {
+                        Ok(event) => client_ids.push(event.client_id()),
+                        Err(mut error) => {
+                            error.shift_lines(lines);
+                            assert_eq!(error.line(), 4);
+                            assert_eq!(error.offset(), 54);
+                        }
+                    }
+                }
+                assert_eq!(reader.header(), first_line);
+                lines += reader.line();
+            }
+            assert_eq!(client_ids, [1, 2, 1, 4]);
+            assert_eq!(lines, 6);
+        }
+    }
+
+    mod source {
+        async fn client_ids(mut source: impl tinypay::events::EventSource) -> Vec<u16> {
+            let mut client_ids = Vec::new();
+            while let Some(event) = source.next_event().await {
+                client_ids.push(event.unwrap().client_id());
+            }
+            client_ids
+        }
+
+        #[tokio::test]
+        async fn formats() {
+            assert_eq!(
+                client_ids(tinypay::events::csv::CsvEventsReader::from_reader(
+                    &b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,1.0\n"[..],
+                ))
+                .await,
+                [1, 2]
+            );
+            assert_eq!(
+                client_ids(tinypay::events::jsonl::JsonlEventsReader::from_reader(
+                    &br#"{"type":"deposit","client":3,"tx":1,"amount":"1.0"}"#[..],
+                ))
+                .await,
+                [3]
+            );
+        }
+    }
+
+    mod jsonl {
+        #[tokio::test]
+        async fn read_error() {
+            // Reading a directory fails on every attempt, the reader stops after the first error
+            let directory = tokio::fs::File::open(std::env::temp_dir()).await.unwrap();
+            let mut reader = tinypay::events::jsonl::JsonlEventsReader::from_reader(directory);
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::IO { .. }))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn read() {
+            let mut reader = tinypay::events::jsonl::JsonlEventsReader::from_reader(
+                &br#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+{"type":"withdrawal","client":1,"tx":2,"amount":0.5}
+
+{"type":"freeze","client":1,"reason":"suspicious activity"}
+{"type":"deposit","client":1,"tx":3,"amount":"-1"}
+{"type":"deposit","client":1}
+"#[..],
+            );
+
+            let Some(Ok(tinypay::events::Event::Deposit(deposit))) = reader.next_event().await
+            else {
+                panic!("expected deposit event");
+            };
+            assert_eq!(deposit.amount, "1.5".parse().unwrap());
+            let Some(Ok(tinypay::events::Event::Withdrawal(withdrawal))) =
+                reader.next_event().await
+            else {
+                panic!("expected withdrawal event");
+            };
+            assert_eq!(withdrawal.amount, "0.5".parse().unwrap());
+            let Some(Ok(tinypay::events::Event::Freeze(freeze))) = reader.next_event().await else {
+                panic!("expected freeze event");
+            };
+            assert_eq!(freeze.reason, "suspicious activity");
+
+            let Some(Err(error)) = reader.next_event().await else {
+                panic!("expected parse error");
+            };
+            assert!(matches!(
+                error,
+                tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Jsonl(
+                        tinypay::events::jsonl::FromJsonLineError::InvalidEvent(
+                            tinypay::events::csv::FromCsvRowError::NonPositiveAmount(_)
+                        )
+                    ),
+                    ..
+                }
+            ));
+            assert_eq!(error.line(), 5);
+            assert_eq!(error.offset(), 166);
+            assert_eq!(
+                error.raw(),
+                r#"{"type":"deposit","client":1,"tx":3,"amount":"-1"}"#
+            );
+
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Jsonl(
+                        tinypay::events::jsonl::FromJsonLineError::InvalidEvent(
+                            tinypay::events::csv::FromCsvRowError::MissingTransactionId
+                        )
+                    ),
+                    line: 6,
+                    ..
+                }))
+            ));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn errors() {
+            let mut reader = tinypay::events::jsonl::JsonlEventsReader::from_reader(
+                &br#"{"type":"deposit","client":"x","tx":1,"amount":"1.0"}
+{"type":"deposit","client":1,"amount":"1.0"}
+{"type":"deposit","client":1,"tx":1,"amount":1.23456}
+{"type":"deposit","client":1,"tx":1,"amount":"NaN"}
+{"type":"withdrawal","client":1,"tx":1}
+{"type":"refund","client":1,"tx":1}
+{"type":"freeze","client":1}
+[1,2]
+{"type":
+"#[..],
+            );
+
+            let mut errors = Vec::new();
+            while let Some(Err(tinypay::events::NextError::Parse {
+                source: tinypay::events::ParseError::Jsonl(source),
+                ..
+            })) = reader.next_event().await
+            {
+                errors.push(source.to_string());
+            }
+            assert_eq!(
+                &errors[..8],
+                [
+                    "invalid client ID: x",
+                    "missing transaction ID",
+                    "amount has more than four decimal places: 1.23456",
+                    "amount is not a finite number: NaN",
+                    "missing amount",
+                    "invalid transaction type: refund",
+                    "missing reason",
+                    "line is not a JSON object",
+                ]
+            );
+            assert!(errors[8].starts_with("invalid JSON: "));
+            assert_eq!(errors.len(), 9);
+        }
+
+        #[tokio::test]
+        async fn invalid_utf8() {
+            let mut reader = tinypay::events::jsonl::JsonlEventsReader::from_reader(
+                &b"{\"type\":\"freeze\",\"client\":1,\"reason\":\"\xFF\"}\n\
+                   {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.0\"}\n"[..],
+            );
+            assert!(matches!(
+                reader.next_event().await,
+                Some(Err(tinypay::events::NextError::Parse {
+                    source: tinypay::events::ParseError::Jsonl(
+                        tinypay::events::jsonl::FromJsonLineError::InvalidUtf8
+                    ),
+                    line: 1,
+                    ..
+                }))
+            ));
+            assert!(matches!(reader.next_event().await, Some(Ok(_))));
+            assert!(reader.next_event().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn write_quarantined() {
+            let mut reader = tinypay::events::jsonl::JsonlEventsReader::from_reader(
+                &b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"0\"}\nnot json\n"[..],
+            );
+            let mut output = Vec::new();
+            let mut writer =
+                tinypay::events::jsonl::QuarantineJsonlWriter::from_writer(&mut output);
+            while let Some(Err(tinypay::events::NextError::Parse {
+                raw,
+                source: tinypay::events::ParseError::Jsonl(source),
+                ..
+            })) = reader.next_event().await
+            {
+                writer.write_quarantined(&raw, &source).unwrap();
+            }
+
+            let lines = String::from_utf8(output).unwrap();
+            let lines = lines
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(lines.len(), 2);
+            assert_eq!(lines[0]["type"], "deposit");
+            assert_eq!(lines[0]["error"], "amount must be positive: 0");
+            assert_eq!(lines[1]["raw"], "not json");
+        }
+    }
+
     mod reduce {
         #[test]
         fn empty() {
@@ -11,42 +1543,42 @@ mod events {
             mod deposit {
                 #[test]
                 fn success() {
-                    let accounts = tinypay::events::reduce(&[tinypay::events::Event::Transaction(
-                        tinypay::events::Transaction {
+                    let accounts = tinypay::events::reduce(&[tinypay::events::Event::Deposit(
+                        tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         },
                     )]);
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 100.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 100.0);
+                    assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
 
                 #[test]
                 fn duplicate() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
                     ]);
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 100.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 100.0);
+                    assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
             }
@@ -55,47 +1587,47 @@ mod events {
                 #[test]
                 fn sufficient() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Withdrawal(tinypay::events::Withdrawal {
                             id: 2,
                             client_id: 1,
-                            amount: -50.0,
+                            amount: "50.0".parse().unwrap(),
                         }),
                     ]);
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 50.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 50.0);
+                    assert_eq!(accounts[0].available, "50.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "50.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
 
                 #[test]
                 fn not_enough() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Withdrawal(tinypay::events::Withdrawal {
                             id: 2,
                             client_id: 1,
-                            amount: -150.0,
+                            amount: "150.0".parse().unwrap(),
                         }),
                     ]);
 
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 100.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 100.0);
+                    assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
             }
@@ -103,24 +1635,24 @@ mod events {
             #[test]
             fn duplicate() {
                 let accounts = tinypay::events::reduce(&[
-                    tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                    tinypay::events::Event::Deposit(tinypay::events::Deposit {
                         id: 1,
                         client_id: 1,
-                        amount: 100.0,
+                        amount: "100.0".parse().unwrap(),
                     }),
-                    tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                    tinypay::events::Event::Deposit(tinypay::events::Deposit {
                         id: 1,
                         client_id: 1,
-                        amount: 100.0,
+                        amount: "100.0".parse().unwrap(),
                     }),
                 ]);
 
                 assert_eq!(accounts.len(), 1);
 
                 assert_eq!(accounts[0].client, 1);
-                assert_eq!(accounts[0].available, 100.0);
-                assert_eq!(accounts[0].held, 0.0);
-                assert_eq!(accounts[0].total, 100.0);
+                assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                 assert!(!accounts[0].locked);
             }
         }
@@ -129,10 +1661,10 @@ mod events {
             #[test]
             fn open() {
                 let accounts = tinypay::events::reduce(&[
-                    tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                    tinypay::events::Event::Deposit(tinypay::events::Deposit {
                         id: 1,
                         client_id: 1,
-                        amount: 100.0,
+                        amount: "100.0".parse().unwrap(),
                     }),
                     tinypay::events::Event::Dispute(tinypay::events::Dispute {
                         transaction_id: 1,
@@ -143,19 +1675,19 @@ mod events {
                 assert_eq!(accounts.len(), 1);
 
                 assert_eq!(accounts[0].client, 1);
-                assert_eq!(accounts[0].available, 0.0);
-                assert_eq!(accounts[0].held, 100.0);
-                assert_eq!(accounts[0].total, 100.0);
+                assert_eq!(accounts[0].available, "0.0".parse().unwrap());
+                assert_eq!(accounts[0].held, "100.0".parse().unwrap());
+                assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                 assert!(!accounts[0].locked);
             }
 
             #[test]
             fn ignored() {
                 let accounts = tinypay::events::reduce(&[
-                    tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                    tinypay::events::Event::Deposit(tinypay::events::Deposit {
                         id: 1,
                         client_id: 1,
-                        amount: 100.0,
+                        amount: "100.0".parse().unwrap(),
                     }),
                     tinypay::events::Event::Dispute(tinypay::events::Dispute {
                         transaction_id: 2,
@@ -166,20 +1698,120 @@ mod events {
                 assert_eq!(accounts.len(), 1);
 
                 assert_eq!(accounts[0].client, 1);
-                assert_eq!(accounts[0].available, 100.0);
-                assert_eq!(accounts[0].held, 0.0);
-                assert_eq!(accounts[0].total, 100.0);
+                assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                 assert!(!accounts[0].locked);
             }
 
+            #[test]
+            fn twice() {
+                let accounts = tinypay::events::reduce(&[
+                    tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                        id: 1,
+                        client_id: 1,
+                        amount: "100.0".parse().unwrap(),
+                    }),
+                    tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                        transaction_id: 1,
+                        client_id: 1,
+                    }),
+                    tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                        transaction_id: 1,
+                        client_id: 1,
+                    }),
+                ]);
+
+                assert_eq!(accounts.len(), 1);
+
+                assert_eq!(accounts[0].client, 1);
+                assert_eq!(accounts[0].available, "0.0".parse().unwrap());
+                assert_eq!(accounts[0].held, "100.0".parse().unwrap());
+                assert_eq!(accounts[0].total, "100.0".parse().unwrap());
+                assert!(!accounts[0].locked);
+            }
+
+            #[test]
+            fn state() {
+                let mut engine = tinypay::events::Engine::new();
+                engine.apply(tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                    id: 1,
+                    client_id: 1,
+                    amount: "100.0".parse().unwrap(),
+                }));
+                assert_eq!(
+                    engine.transaction_state(1, 1),
+                    Some(tinypay::events::TransactionState::Settled)
+                );
+
+                engine.apply(tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                    transaction_id: 1,
+                    client_id: 1,
+                }));
+                assert_eq!(
+                    engine.transaction_state(1, 1),
+                    Some(tinypay::events::TransactionState::Disputed)
+                );
+
+                assert_eq!(
+                    engine.apply(tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                        transaction_id: 1,
+                        client_id: 1,
+                    })),
+                    tinypay::events::Outcome::Rejected(
+                        tinypay::events::RejectionReason::AlreadyDisputed
+                    )
+                );
+            }
+
+            #[test]
+            fn transitions() {
+                use tinypay::events::RejectionReason;
+                use tinypay::events::TransactionState;
+
+                assert_eq!(
+                    TransactionState::Settled.dispute(),
+                    Ok(TransactionState::Disputed)
+                );
+                assert_eq!(
+                    TransactionState::Disputed.dispute(),
+                    Err(RejectionReason::AlreadyDisputed)
+                );
+                assert_eq!(
+                    TransactionState::Resolved.dispute(),
+                    Err(RejectionReason::DisputeClosed)
+                );
+                assert_eq!(
+                    TransactionState::ChargedBack.dispute(),
+                    Err(RejectionReason::DisputeClosed)
+                );
+
+                assert_eq!(
+                    TransactionState::Disputed.resolve(),
+                    Ok(TransactionState::Resolved)
+                );
+                assert_eq!(
+                    TransactionState::Disputed.chargeback(),
+                    Ok(TransactionState::ChargedBack)
+                );
+                for state in [
+                    TransactionState::Settled,
+                    TransactionState::Resolved,
+                    TransactionState::ChargedBack,
+                ] {
+                    assert_eq!(state.resolve(), Err(RejectionReason::NotDisputed));
+                    assert_eq!(state.chargeback(), Err(RejectionReason::NotDisputed));
+                }
+            }
+
             mod resolve {
                 #[test]
                 fn ignored() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
                         tinypay::events::Event::Dispute(tinypay::events::Dispute {
                             transaction_id: 1,
@@ -194,19 +1826,19 @@ mod events {
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 0.0);
-                    assert_eq!(accounts[0].held, 100.0);
-                    assert_eq!(accounts[0].total, 100.0);
+                    assert_eq!(accounts[0].available, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
 
                 #[test]
                 fn success() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
                         tinypay::events::Event::Dispute(tinypay::events::Dispute {
                             transaction_id: 1,
@@ -221,21 +1853,71 @@ mod events {
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 100.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 100.0);
+                    assert_eq!(accounts[0].available, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "100.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
+
+                #[test]
+                fn redispute() {
+                    let mut engine = tinypay::events::Engine::new();
+                    for event in [
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                            id: 1,
+                            client_id: 1,
+                            amount: "100.0".parse().unwrap(),
+                        }),
+                        tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                        tinypay::events::Event::Resolve(tinypay::events::Resolve {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                    ] {
+                        assert_eq!(engine.apply(event), tinypay::events::Outcome::Applied);
+                    }
+                    assert_eq!(
+                        engine.transaction_state(1, 1),
+                        Some(tinypay::events::TransactionState::Resolved)
+                    );
+
+                    assert_eq!(
+                        engine.apply(tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                            transaction_id: 1,
+                            client_id: 1,
+                        })),
+                        tinypay::events::Outcome::Rejected(
+                            tinypay::events::RejectionReason::DisputeClosed
+                        )
+                    );
+                    assert_eq!(
+                        engine.apply(tinypay::events::Event::Resolve(tinypay::events::Resolve {
+                            transaction_id: 1,
+                            client_id: 1,
+                        })),
+                        tinypay::events::Outcome::Rejected(
+                            tinypay::events::RejectionReason::NotDisputed
+                        )
+                    );
+
+                    let account = engine.account(1).unwrap();
+                    assert_eq!(account.available, "100.0".parse().unwrap());
+                    assert_eq!(account.held, "0.0".parse().unwrap());
+                    assert_eq!(account.total, "100.0".parse().unwrap());
+                }
             }
 
             mod chargeback {
                 #[test]
                 fn illegal() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
                         tinypay::events::Event::Dispute(tinypay::events::Dispute {
                             transaction_id: 1,
@@ -250,24 +1932,24 @@ mod events {
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, 0.0);
-                    assert_eq!(accounts[0].held, 0.0);
-                    assert_eq!(accounts[0].total, 0.0);
+                    assert_eq!(accounts[0].available, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "0.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "0.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
 
                 #[test]
                 fn legal() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Withdrawal(tinypay::events::Withdrawal {
                             id: 2,
                             client_id: 1,
-                            amount: -50.0,
+                            amount: "50.0".parse().unwrap(),
                         }),
                         tinypay::events::Event::Dispute(tinypay::events::Dispute {
                             transaction_id: 1,
@@ -282,24 +1964,24 @@ mod events {
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, -50.0);
-                    assert_eq!(accounts[0].held, 100.0);
-                    assert_eq!(accounts[0].total, 50.0);
+                    assert_eq!(accounts[0].available, "-50.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "50.0".parse().unwrap());
                     assert!(accounts[0].locked);
                 }
 
                 #[test]
                 fn ignored() {
                     let accounts = tinypay::events::reduce(&[
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
                             id: 1,
                             client_id: 1,
-                            amount: 100.0,
+                            amount: "100.0".parse().unwrap(),
                         }),
-                        tinypay::events::Event::Transaction(tinypay::events::Transaction {
+                        tinypay::events::Event::Withdrawal(tinypay::events::Withdrawal {
                             id: 2,
                             client_id: 1,
-                            amount: -50.0,
+                            amount: "50.0".parse().unwrap(),
                         }),
                         tinypay::events::Event::Dispute(tinypay::events::Dispute {
                             transaction_id: 1,
@@ -314,12 +1996,920 @@ mod events {
                     assert_eq!(accounts.len(), 1);
 
                     assert_eq!(accounts[0].client, 1);
-                    assert_eq!(accounts[0].available, -50.0);
-                    assert_eq!(accounts[0].held, 100.0);
-                    assert_eq!(accounts[0].total, 50.0);
+                    assert_eq!(accounts[0].available, "-50.0".parse().unwrap());
+                    assert_eq!(accounts[0].held, "100.0".parse().unwrap());
+                    assert_eq!(accounts[0].total, "50.0".parse().unwrap());
                     assert!(!accounts[0].locked);
                 }
+
+                #[test]
+                fn final_state() {
+                    let mut engine = tinypay::events::Engine::new();
+                    for event in [
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                            id: 1,
+                            client_id: 1,
+                            amount: "100.0".parse().unwrap(),
+                        }),
+                        tinypay::events::Event::Deposit(tinypay::events::Deposit {
+                            id: 2,
+                            client_id: 1,
+                            amount: "50.0".parse().unwrap(),
+                        }),
+                        tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                        tinypay::events::Event::Chargeback(tinypay::events::Chargeback {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                    ] {
+                        assert_eq!(engine.apply(event), tinypay::events::Outcome::Applied);
+                    }
+                    assert_eq!(
+                        engine.transaction_state(1, 1),
+                        Some(tinypay::events::TransactionState::ChargedBack)
+                    );
+
+                    for event in [
+                        tinypay::events::Event::Dispute(tinypay::events::Dispute {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                        tinypay::events::Event::Chargeback(tinypay::events::Chargeback {
+                            transaction_id: 1,
+                            client_id: 1,
+                        }),
+                    ] {
+                        assert!(matches!(
+                            engine.apply(event),
+                            tinypay::events::Outcome::Rejected(_)
+                        ));
+                    }
+
+                    let account = engine.account(1).unwrap();
+                    assert_eq!(account.available, "50.0".parse().unwrap());
+                    assert_eq!(account.held, "0.0".parse().unwrap());
+                    assert_eq!(account.total, "50.0".parse().unwrap());
+                }
             }
         }
     }
 }
+
+mod pipeline {
+    const INPUT: &[u8] = b"type,client,tx,amount
+deposit,3,1,3.0
+deposit,1,2,1.0
+deposit,2,3,2.0
+withdrawal,1,4,5.0
+deposit,5,5,5.0
+";
+
+    #[tokio::test]
+    async fn run() {
+        let processor =
+            tinypay::pipeline::ShardedProcessor::new(&tinypay::policy::Policy::default(), 3, 2);
+        let accounts = processor
+            .run(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                INPUT,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|account| (account.client, account.total))
+                .collect::<Vec<_>>(),
+            [
+                (1, "1.0".parse().unwrap()),
+                (2, "2.0".parse().unwrap()),
+                (3, "3.0".parse().unwrap()),
+                (5, "5.0".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn outcomes() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let engines = (0..2).map(|_| tinypay::events::Engine::default()).collect();
+        let processor = tinypay::pipeline::ShardedProcessor::start(
+            engines,
+            tinypay::pipeline::Config {
+                outcomes: Some(tx),
+                ..Default::default()
+            },
+        );
+        processor
+            .process(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                INPUT,
+            ))
+            .await
+            .unwrap();
+        processor.finish().await.unwrap();
+
+        let mut rejected = Vec::new();
+        while let Some((event, outcome)) = rx.recv().await {
+            if let tinypay::events::Outcome::Rejected(reason) = outcome {
+                rejected.push((event.client_id(), reason));
+            }
+        }
+        assert_eq!(
+            rejected,
+            [(1, tinypay::events::RejectionReason::InsufficientFunds)]
+        );
+    }
+
+    #[tokio::test]
+    async fn inline() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let mut processor = tinypay::pipeline::InlineProcessor::start(
+            tinypay::events::Engine::default(),
+            tinypay::pipeline::Config {
+                outcomes: Some(tx),
+                ..Default::default()
+            },
+        );
+        processor
+            .process(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                INPUT,
+            ))
+            .await
+            .unwrap();
+        let engine = processor.finish();
+        assert_eq!(
+            engine
+                .accounts()
+                .map(|account| (account.client, account.total))
+                .collect::<Vec<_>>(),
+            [
+                (1, "1.0".parse().unwrap()),
+                (2, "2.0".parse().unwrap()),
+                (3, "3.0".parse().unwrap()),
+                (5, "5.0".parse().unwrap()),
+            ]
+        );
+
+        let mut rejected = Vec::new();
+        while let Some((event, outcome)) = rx.recv().await {
+            if let tinypay::events::Outcome::Rejected(reason) = outcome {
+                rejected.push((event.client_id(), reason));
+            }
+        }
+        assert_eq!(
+            rejected,
+            [(1, tinypay::events::RejectionReason::InsufficientFunds)]
+        );
+    }
+
+    #[tokio::test]
+    async fn error() {
+        let processor =
+            tinypay::pipeline::ShardedProcessor::new(&tinypay::policy::Policy::default(), 2, 1);
+        let error = processor
+            .run(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                &b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\n"[..],
+            ))
+            .await
+            .unwrap_err();
+        let tinypay::pipeline::Error::Read(error) = error else {
+            panic!("expected read error");
+        };
+        assert_eq!(error.line(), 3);
+    }
+
+    #[tokio::test]
+    async fn journal_error() {
+        // Every write to /dev/full fails as if the disk was full
+        let journal = tinypay::events::journal::Journal::new(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open("/dev/full")
+                .unwrap(),
+            0,
+        );
+        let engines = (0..2).map(|_| tinypay::events::Engine::default()).collect();
+        let processor = tinypay::pipeline::ShardedProcessor::start(
+            engines,
+            tinypay::pipeline::Config {
+                journal: Some(std::sync::Arc::new(std::sync::Mutex::new(journal))),
+                ..Default::default()
+            },
+        );
+        let router = processor.router();
+
+        assert!(matches!(
+            router.submit(crate::events::deposit(1, "1.0")).await,
+            Err(tinypay::pipeline::Error::Stopped)
+        ));
+        assert!(matches!(
+            router.account(1).await,
+            Err(tinypay::pipeline::Error::Stopped)
+        ));
+        // Workers of other clients keep running
+        assert!(matches!(router.account(2).await, Ok(None)));
+
+        drop(router);
+        assert!(matches!(
+            processor.finish().await,
+            Err(tinypay::pipeline::Error::Journal(_))
+        ));
+    }
+
+    #[test]
+    fn balanced() {
+        let mut load = tinypay::pipeline::Load::default();
+        load.add(0, 100);
+        for client_id in [2, 4, 6] {
+            load.add(client_id, 10);
+        }
+
+        let modulo = tinypay::pipeline::Assignment::modulo(2);
+        assert_eq!(load.shards(&modulo), [130, 0]);
+        assert_eq!(load.imbalance(&modulo), 2.0);
+
+        let balanced = tinypay::pipeline::Assignment::balanced(2, &load);
+        assert_eq!(load.shards(&balanced), [100, 30]);
+        assert!(load.imbalance(&balanced) < 1.6);
+        // Clients without load keep their modulo shard
+        assert_eq!(balanced.shard(7), 1);
+    }
+
+    fn snapshot(engines: &[tinypay::events::Engine]) -> tinypay::events::snapshot::Snapshot {
+        tinypay::events::snapshot::Snapshot::new(
+            engines
+                .iter()
+                .flat_map(|engine| engine.snapshot().clients)
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn rebalance() {
+        let input = |from: u32| {
+            (from..from + 10)
+                .flat_map(|id| {
+                    [
+                        (0, id * 4),
+                        (2, id * 4 + 1),
+                        (4, id * 4 + 2),
+                        (0, id * 4 + 3),
+                    ]
+                })
+                .map(|(client, tx)| format!("deposit,{},{},1.0\n", client, tx))
+                .collect::<String>()
+        };
+
+        let processor =
+            tinypay::pipeline::ShardedProcessor::new(&tinypay::policy::Policy::default(), 2, 1);
+        processor
+            .process(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                std::io::Cursor::new(input(0)),
+            ))
+            .await
+            .unwrap();
+        let modulo = processor.router().assignment().clone();
+        let engines = processor.finish().await.unwrap();
+        let load = tinypay::pipeline::Load::from_snapshot(&snapshot(&engines));
+        assert_eq!(load.client(0), 20);
+        assert_eq!(load.total(), 40);
+        assert_eq!(load.imbalance(&modulo), 2.0);
+
+        let assignment = tinypay::pipeline::Assignment::balanced(2, &load);
+        let engines = tinypay::pipeline::redistribute(engines, &assignment);
+        let processor = tinypay::pipeline::ShardedProcessor::start(
+            engines,
+            tinypay::pipeline::Config {
+                assignment: Some(assignment),
+                ..Default::default()
+            },
+        );
+        processor
+            .process(&mut tinypay::events::csv::CsvEventsReader::from_reader(
+                std::io::Cursor::new(input(10)),
+            ))
+            .await
+            .unwrap();
+        let assignment = processor.router().assignment().clone();
+        let engines = processor.finish().await.unwrap();
+        assert_eq!(
+            tinypay::pipeline::Load::from_snapshot(&snapshot(&engines)).imbalance(&assignment),
+            1.0
+        );
+
+        let accounts = tinypay::pipeline::merge(engines)
+            .map(|account| (account.client, account.total))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            accounts,
+            [
+                (0, "40.0".parse().unwrap()),
+                (2, "20.0".parse().unwrap()),
+                (4, "20.0".parse().unwrap()),
+            ]
+        );
+    }
+}
+
+mod policy {
+    #[test]
+    fn default() {
+        let policy: tinypay::policy::Policy = "".parse().unwrap();
+        assert_eq!(policy, tinypay::policy::Policy::default());
+        assert_eq!(policy.lock_on, tinypay::policy::LockOn::NegativeBalance);
+        assert!(policy.disputable_withdrawals);
+        assert!(policy.negative_available_on_dispute);
+        assert_eq!(
+            policy.locked_accounts,
+            tinypay::policy::LockedAccounts::RejectAll
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let policy: tinypay::policy::Policy = r#"
+            lock_on = "any_chargeback"
+            disputable_withdrawals = false
+            negative_available_on_dispute = false
+            locked_accounts = "accept_deposits"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            policy,
+            tinypay::policy::Policy {
+                lock_on: tinypay::policy::LockOn::AnyChargeback,
+                disputable_withdrawals: false,
+                negative_available_on_dispute: false,
+                locked_accounts: tinypay::policy::LockedAccounts::AcceptDeposits,
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!("lock_always = true"
+            .parse::<tinypay::policy::Policy>()
+            .is_err());
+    }
+
+    mod engine {
+
+        #[test]
+        fn lock_on_any_chargeback() {
+            let mut engine = tinypay::events::Engine::with_policy(tinypay::policy::Policy {
+                lock_on: tinypay::policy::LockOn::AnyChargeback,
+                ..Default::default()
+            });
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::dispute(1));
+            assert_eq!(
+                engine.apply(crate::events::chargeback(1)),
+                tinypay::events::Outcome::Applied
+            );
+
+            let account = engine.account(1).unwrap();
+            assert_eq!(account.total, "0.0".parse().unwrap());
+            assert!(account.locked);
+        }
+
+        #[test]
+        fn withdrawals_not_disputable() {
+            let mut engine = tinypay::events::Engine::with_policy(tinypay::policy::Policy {
+                disputable_withdrawals: false,
+                ..Default::default()
+            });
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::withdrawal(2, "50.0"));
+            assert_eq!(
+                engine.apply(crate::events::dispute(2)),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::NotDisputable)
+            );
+            assert_eq!(
+                engine.apply(crate::events::dispute(1)),
+                tinypay::events::Outcome::Applied
+            );
+        }
+
+        #[test]
+        fn no_negative_available_on_dispute() {
+            let mut engine = tinypay::events::Engine::with_policy(tinypay::policy::Policy {
+                negative_available_on_dispute: false,
+                ..Default::default()
+            });
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::withdrawal(2, "50.0"));
+            assert_eq!(
+                engine.apply(crate::events::dispute(1)),
+                tinypay::events::Outcome::Rejected(
+                    tinypay::events::RejectionReason::InsufficientFunds
+                )
+            );
+
+            let account = engine.account(1).unwrap();
+            assert_eq!(account.available, "50.0".parse().unwrap());
+            assert_eq!(account.held, "0.0".parse().unwrap());
+        }
+
+        #[test]
+        fn locked_accounts_accept_deposits() {
+            let mut engine = tinypay::events::Engine::with_policy(tinypay::policy::Policy {
+                lock_on: tinypay::policy::LockOn::AnyChargeback,
+                locked_accounts: tinypay::policy::LockedAccounts::AcceptDeposits,
+                ..Default::default()
+            });
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::deposit(2, "10.0"));
+            engine.apply(crate::events::dispute(1));
+            engine.apply(crate::events::chargeback(1));
+
+            assert_eq!(
+                engine.apply(crate::events::deposit(3, "5.0")),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(
+                engine.apply(crate::events::withdrawal(4, "5.0")),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+            assert_eq!(
+                engine.apply(crate::events::dispute(2)),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+            assert_eq!(engine.account(1).unwrap().total, "15.0".parse().unwrap());
+        }
+
+        #[test]
+        fn locked_accounts_accept_disputes() {
+            let mut engine = tinypay::events::Engine::with_policy(tinypay::policy::Policy {
+                lock_on: tinypay::policy::LockOn::AnyChargeback,
+                locked_accounts: tinypay::policy::LockedAccounts::AcceptDisputes,
+                ..Default::default()
+            });
+            engine.apply(crate::events::deposit(1, "100.0"));
+            engine.apply(crate::events::deposit(2, "10.0"));
+            engine.apply(crate::events::dispute(1));
+            engine.apply(crate::events::chargeback(1));
+
+            assert_eq!(
+                engine.apply(crate::events::deposit(3, "5.0")),
+                tinypay::events::Outcome::Rejected(tinypay::events::RejectionReason::AccountLocked)
+            );
+            assert_eq!(
+                engine.apply(crate::events::dispute(2)),
+                tinypay::events::Outcome::Applied
+            );
+            assert_eq!(engine.account(1).unwrap().held, "10.0".parse().unwrap());
+        }
+    }
+}
+
+mod cli {
+    // Runs `tinypay` on a temporary file with the given contents
+    fn run(name: &str, input: impl AsRef<[u8]>, args: &[&str]) -> std::process::Output {
+        let path =
+            std::env::temp_dir().join(format!("tinypay-cli-{}-{}.csv", name, std::process::id()));
+        std::fs::write(&path, input).unwrap();
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+            .arg(&path)
+            .args(args)
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        output
+    }
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,1.0\n\
+                         deposit,1,2,abc\n\
+                         deposit,1,3,2.0\n";
+
+    #[test]
+    fn lenient() {
+        let output = run("lenient", INPUT, &[]);
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn strict() {
+        let output = run("strict", INPUT, &["--strict"]);
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("line 3, offset 38: failed to parse event: invalid amount: abc"));
+    }
+
+    #[test]
+    fn read_error() {
+        // Reading a directory fails on every attempt, which must not be retried forever
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+            .arg(std::env::temp_dir())
+            .args(["--workers", "1"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn pipe() {
+        // Inputs that can not be seeked are never split into parts
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+            .args(["/dev/stdin", "--workers", "4"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), INPUT.as_bytes()).unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn split_quoted_field() {
+        // A quoted line break right after the first split point of parallel parsing
+        let mut input = String::from("type,client,tx,amount,reason\n");
+        let mut tx = 0;
+        while input.len() < 8 * 1024 * 1024 - 64 {
+            tx += 1;
+            input.push_str(&format!("deposit,1,{},1.0,\n", tx));
+        }
+        input.push_str(&format!(
+            "freeze,99,,,\"{}\nline two\"\n",
+            "x".repeat(8 * 1024 * 1024 + 16 - input.len())
+        ));
+        input.push_str("deposit,2,abc,1.0,\n");
+        for tx in tx + 1..tx + 1000 {
+            input.push_str(&format!("deposit,2,{},1.0,\n", tx));
+        }
+
+        let sequential = run(
+            "split-quoted-1",
+            &input,
+            &["--workers", "1", "--error-summary"],
+        );
+        assert!(sequential.status.success());
+        assert!(String::from_utf8(sequential.stdout.clone())
+            .unwrap()
+            .contains("99,0.0000,0.0000,0.0000,true"));
+        assert!(String::from_utf8(sequential.stderr.clone())
+            .unwrap()
+            .contains("invalid transaction ID: abc"));
+
+        let parallel = run(
+            "split-quoted-4",
+            &input,
+            &["--workers", "4", "--error-summary"],
+        );
+        assert!(parallel.status.success());
+        assert_eq!(parallel.stdout, sequential.stdout);
+        assert_eq!(parallel.stderr, sequential.stderr);
+    }
+
+    #[test]
+    fn max_errors() {
+        assert!(run("max-errors-1", INPUT, &["--max-errors", "1"])
+            .status
+            .success());
+        assert!(!run("max-errors-0", INPUT, &["--max-errors", "0"])
+            .status
+            .success());
+    }
+
+    #[test]
+    fn workers() {
+        let input = (1..=50)
+            .map(|client| {
+                format!(
+                    "deposit,{},{},1.0\nwithdrawal,{},{},2.0\n",
+                    client,
+                    client,
+                    client,
+                    client + 50
+                )
+            })
+            .collect::<String>();
+
+        let single = run("workers-1", &input, &["--workers", "1"]);
+        assert!(single.status.success());
+        let sharded = run(
+            "workers-4",
+            &input,
+            &["--workers", "4", "--queue-depth", "1"],
+        );
+        assert!(sharded.status.success());
+        assert_eq!(single.stdout, sharded.stdout);
+
+        assert!(!run("workers-0", &input, &["--workers", "0"])
+            .status
+            .success());
+    }
+
+    #[test]
+    fn sorted() {
+        let input = (1..=100)
+            .rev()
+            .map(|client| format!("deposit,{},{},{}\n", client, client, client % 7 + 1))
+            .collect::<String>();
+
+        let output = run("sorted", &input, &[]);
+        let clients = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse::<u16>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, (1..=100).collect::<Vec<_>>());
+
+        let output = run("sorted-total", &input, &["--sort", "total"]);
+        let rows = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let mut fields = line.split(',');
+                let client = fields.next().unwrap().parse::<u16>().unwrap();
+                let total = fields.nth(2).unwrap().to_string();
+                (total, client)
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = rows.clone();
+        sorted.sort();
+        assert_eq!(rows.len(), 100);
+        assert_eq!(rows, sorted);
+    }
+
+    #[test]
+    fn jsonl() {
+        let path = std::env::temp_dir().join(format!("tinypay-cli-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5\"}\n",
+        )
+        .unwrap();
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+            .arg(&path)
+            .output()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n"
+        );
+
+        let output = run(
+            "jsonl",
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5\"}\n",
+            &["--input-format", "jsonl"],
+        );
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn quarantine() {
+        let path = std::env::temp_dir().join(format!(
+            "tinypay-cli-quarantined-{}.csv",
+            std::process::id()
+        ));
+        let output = run(
+            "quarantine",
+            INPUT,
+            &["--quarantine", path.to_str().unwrap()],
+        );
+        assert!(output.status.success());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,error\ndeposit,1,2,abc,invalid amount: abc\n"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let path = std::env::temp_dir().join(format!(
+            "tinypay-cli-quarantined-utf8-{}.csv",
+            std::process::id()
+        ));
+        let output = run(
+            "invalid-utf8",
+            b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\xFF\n",
+            &["--quarantine", path.to_str().unwrap()],
+        );
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,error\ndeposit,1,2,\u{FFFD},record is not valid UTF-8\n"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+mod serve {
+    use std::io::{BufRead, Read, Write};
+
+    #[test]
+    fn tcp() {
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let address = line
+            .trim()
+            .strip_prefix("Listening for TCP on ")
+            .unwrap()
+            .to_string();
+
+        let connections = [
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\nwithdrawal,1,3,3.0\n",
+            "deposit,2,4,7.5\ninvalid\ndeposit,2,5,2.5\nwithdrawal,2,6,100.0\n",
+        ]
+        .map(|input| {
+            let address = address.clone();
+            std::thread::spawn(move || {
+                let mut stream = std::net::TcpStream::connect(address).unwrap();
+                stream.write_all(input.as_bytes()).unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                // The server closes the connection once all of its events are routed
+                stream.read_to_end(&mut Vec::new()).unwrap();
+            })
+        });
+        for connection in connections {
+            connection.join().unwrap();
+        }
+
+        let status = std::process::Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n\
+             1,12.0000,0.0000,12.0000,false\n\
+             2,10.0000,0.0000,10.0000,false\n"
+        );
+    }
+}
+
+mod http {
+    use std::io::{BufRead, Read, Write};
+
+    // Runs `tinypay serve --http` on a random port and kills it when dropped
+    struct Server {
+        child: std::process::Child,
+        address: String,
+    }
+
+    impl Server {
+        fn start() -> Self {
+            let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_tinypay"))
+                .args(["serve", "--http", "127.0.0.1:0"])
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+
+            let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+            let mut line = String::new();
+            stderr.read_line(&mut line).unwrap();
+            let address = line
+                .trim()
+                .strip_prefix("Listening for HTTP on ")
+                .unwrap()
+                .to_string();
+            Self { child, address }
+        }
+
+        fn request(&self, method: &str, path: &str, body: &str) -> (u16, serde_json::Value) {
+            let mut stream = std::net::TcpStream::connect(&self.address).unwrap();
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                path,
+                self.address,
+                body.len(),
+                body
+            )
+            .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+            let body = serde_json::from_str(body).unwrap_or(serde_json::Value::Null);
+            (status, body)
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    #[test]
+    fn events_and_accounts() {
+        let server = Server::start();
+
+        let (status, body) = server.request(
+            "POST",
+            "/events",
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"10.5"}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({"outcome": "applied"}));
+
+        let (status, body) = server.request(
+            "POST",
+            "/events",
+            r#"[
+                {"type":"deposit","client":2,"tx":2,"amount":3},
+                {"type":"withdrawal","client":1,"tx":3,"amount":"20"},
+                {"type":"dispute","client":1,"tx":1}
+            ]"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"outcome": "applied"},
+                {"outcome": "rejected", "reason": "insufficient_funds"},
+                {"outcome": "applied"}
+            ])
+        );
+
+        let (status, body) = server.request("GET", "/accounts/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "client": 1,
+                "available": "0.0000",
+                "held": "10.5000",
+                "total": "10.5000",
+                "locked": false,
+                "closed": false
+            })
+        );
+
+        let (status, _) = server.request("GET", "/accounts/3", "");
+        assert_eq!(status, 404);
+
+        let (status, body) = server.request("GET", "/accounts?limit=1", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["accounts"].as_array().unwrap().len(), 1);
+        assert_eq!(body["accounts"][0]["client"], 1);
+        assert_eq!(body["next"], 1);
+
+        let (status, body) = server.request("GET", "/accounts?after=1&limit=1", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["accounts"][0]["client"], 2);
+        assert_eq!(body["accounts"][0]["available"], "3.0000");
+
+        let (status, body) = server.request("GET", "/accounts?after=2", "");
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({"accounts": []}));
+    }
+
+    #[test]
+    fn invalid_event() {
+        let server = Server::start();
+
+        let (status, _) = server.request(
+            "POST",
+            "/events",
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"0.00001"}"#,
+        );
+        assert_eq!(status, 422);
+    }
+}